@@ -0,0 +1,99 @@
+// Rows serialized as the elements of the "rows" array of an insertAll request body
+pub struct Batch {
+    pub first_index: usize, // Index of the first row in the rows passed to insert_rows
+    pub rows: Vec<String>,
+}
+
+// Bytes of the request body besides the rows: {"rows":[]}
+const BODY_OVERHEAD_BYTE_COUNT: usize = 11;
+
+impl Batch {
+    pub fn to_request_body(&self) -> String {
+        return format!("{{\"rows\":[{}]}}", self.rows.join(","));
+    }
+}
+
+// Splits the rows greedily so that every batch is within both limits.
+// A row exceeding the byte limit by itself gets a batch of its own and is left to the API to reject.
+pub fn split_into_batches(
+    rows: Vec<String>,
+    max_batch_rows: usize,
+    max_batch_bytes: usize,
+) -> Vec<Batch> {
+    let mut batches = vec![];
+    let mut current = Batch {
+        first_index: 0,
+        rows: vec![],
+    };
+    let mut current_byte_count = BODY_OVERHEAD_BYTE_COUNT;
+    for (index, row) in rows.into_iter().enumerate() {
+        // One more byte for the comma between the rows
+        let row_byte_count = row.len() + 1;
+        let exceeds_limits = current.rows.len() >= max_batch_rows
+            || current_byte_count + row_byte_count > max_batch_bytes;
+        if !current.rows.is_empty() && exceeds_limits {
+            batches.push(current);
+            current = Batch {
+                first_index: index,
+                rows: vec![],
+            };
+            current_byte_count = BODY_OVERHEAD_BYTE_COUNT;
+        }
+        current.rows.push(row);
+        current_byte_count += row_byte_count;
+    }
+    if !current.rows.is_empty() {
+        batches.push(current);
+    }
+    return batches;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rows(lengths: &[usize]) -> Vec<String> {
+        return lengths.iter().map(|&length| "x".repeat(length)).collect();
+    }
+
+    fn batch_rows(batches: &[Batch]) -> Vec<(usize, usize)> {
+        return batches
+            .iter()
+            .map(|batch| (batch.first_index, batch.rows.len()))
+            .collect();
+    }
+
+    #[test]
+    fn split_into_batches_limits_the_rows() {
+        let batches = split_into_batches(rows(&[1, 1, 1, 1, 1]), 2, 1000);
+        assert_eq!(batch_rows(&batches), vec![(0, 2), (2, 2), (4, 1)]);
+    }
+
+    #[test]
+    fn split_into_batches_limits_the_bytes() {
+        // Each row takes 10 bytes with its comma, so two rows fit into 11 + 20 bytes
+        let batches = split_into_batches(rows(&[9, 9, 9]), 100, 31);
+        assert_eq!(batch_rows(&batches), vec![(0, 2), (2, 1)]);
+        assert!(batches[0].to_request_body().len() <= 31);
+    }
+
+    #[test]
+    fn split_into_batches_puts_an_oversized_row_alone() {
+        let batches = split_into_batches(rows(&[1, 50, 1]), 100, 20);
+        assert_eq!(batch_rows(&batches), vec![(0, 1), (1, 1), (2, 1)]);
+    }
+
+    #[test]
+    fn split_into_batches_of_no_rows_is_empty() {
+        assert!(split_into_batches(vec![], 10, 100).is_empty());
+    }
+
+    #[test]
+    fn to_request_body_joins_the_rows() {
+        let batch = Batch {
+            first_index: 0,
+            rows: vec!["{\"a\":1}".to_string(), "{\"a\":2}".to_string()],
+        };
+        assert_eq!(batch.to_request_body(), "{\"rows\":[{\"a\":1},{\"a\":2}]}");
+    }
+}
@@ -1,11 +1,171 @@
 use serde::{Deserialize, Serialize};
 
+// An element of the "rows" array of the insertAll request body
 #[derive(Serialize, Deserialize)]
-pub struct InsertRowsRequestBody<T> {
-    pub rows: Vec<InsertRowsRequestBodyRow<T>>,
+#[serde(rename_all = "camelCase")]
+pub struct InsertRowsRequestBodyRow<T> {
+    pub insert_id: String, // BigQuery drops a row whose insertId it has seen in the last minute
+    pub json: T,
 }
 
 #[derive(Serialize, Deserialize)]
-pub struct InsertRowsRequestBodyRow<T> {
-    pub json: T,
+#[serde(rename_all = "camelCase")]
+pub struct InsertRowsResponseBody {
+    // Omitted when every row is inserted
+    #[serde(default)]
+    pub insert_errors: Vec<InsertRowsResponseBodyInsertError>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct InsertRowsResponseBodyInsertError {
+    pub index: usize,
+    pub errors: Vec<ErrorProto>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ErrorResponseBody {
+    pub error: ErrorResponseBodyError,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ErrorResponseBodyError {
+    pub code: u16,
+    pub message: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ErrorProto {
+    #[serde(default)]
+    pub reason: String,
+    #[serde(default)]
+    pub location: String,
+    #[serde(default)]
+    pub message: String,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Dataset {
+    pub dataset_reference: DatasetReference,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub location: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DatasetReference {
+    pub project_id: String,
+    pub dataset_id: String,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Table {
+    pub table_reference: TableReference,
+    #[serde(default)]
+    pub schema: TableSchema,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TableReference {
+    pub project_id: String,
+    pub dataset_id: String,
+    pub table_id: String,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+pub struct TableSchema {
+    #[serde(default)]
+    pub fields: Vec<TableFieldSchema>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct TableFieldSchema {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub field_type: String, // e.g. "STRING"
+    // The API omits the mode of a NULLABLE field
+    #[serde(default = "default_mode")]
+    pub mode: String,
+}
+
+fn default_mode() -> String {
+    return String::from("NULLABLE");
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct JobRequestBody {
+    pub configuration: JobConfiguration,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct JobConfiguration {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub load: Option<JobConfigurationLoad>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub query: Option<JobConfigurationQuery>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JobConfigurationLoad {
+    pub destination_table: TableReference,
+    pub source_format: String,     // "NEWLINE_DELIMITED_JSON"
+    pub write_disposition: String, // "WRITE_APPEND" | "WRITE_TRUNCATE" | "WRITE_EMPTY"
+    // Required to create the destination table
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub schema: Option<TableSchema>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JobConfigurationQuery {
+    pub query: String,
+    pub use_legacy_sql: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Job {
+    pub job_reference: JobReference,
+    pub status: JobStatus,
+    pub statistics: Option<JobStatistics>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JobReference {
+    pub project_id: String,
+    pub job_id: String,
+    pub location: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JobStatus {
+    pub state: String, // "PENDING" | "RUNNING" | "DONE"
+    // Present when the job failed
+    pub error_result: Option<ErrorProto>,
+    // Every error encountered, which may not have failed the job by itself
+    #[serde(default)]
+    pub errors: Vec<ErrorProto>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct JobStatistics {
+    pub load: Option<JobStatisticsLoad>,
+    pub query: Option<JobStatisticsQuery>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JobStatisticsLoad {
+    pub output_rows: Option<String>, // int64 is encoded as a string
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JobStatisticsQuery {
+    pub num_dml_affected_rows: Option<String>, // int64 is encoded as a string
 }
@@ -0,0 +1,237 @@
+use reqwest::header::{CONTENT_RANGE, CONTENT_TYPE, LOCATION};
+use reqwest::StatusCode;
+use serde::Serialize;
+use std::error::Error;
+use std::ops::Range;
+use std::time::Duration;
+
+use crate::bq_client::{defs, error_proto_to_reason, response_to_error, BqClient, API_ROOT};
+
+const UPLOAD_API_ROOT: &str = "https://bigquery.googleapis.com/upload/bigquery/v2/";
+// The data of a load job is sent in a resumable upload, in requests of up to this many bytes,
+// so that a long backfill never builds a request body beyond the API limits.
+// Every chunk but the last must be a multiple of 256 KiB.
+const UPLOAD_CHUNK_BYTE_COUNT: usize = 32 * 256 * 1024;
+
+const INITIAL_POLL_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+pub struct JobReport {
+    pub job_id: String,
+    pub affected_row_count: usize, // Rows loaded by a load job, or changed by a DML query
+    pub errors: Vec<String>,       // Empty when the job succeeded
+}
+
+impl JobReport {
+    pub fn is_success(&self) -> bool {
+        return self.errors.is_empty();
+    }
+
+    pub fn empty() -> Self {
+        return JobReport {
+            job_id: String::from(""),
+            affected_row_count: 0,
+            errors: vec![],
+        };
+    }
+}
+
+impl BqClient {
+    // Uploads the rows as newline-delimited JSON in a load job appending to the table,
+    // and waits until the job finishes
+    pub async fn load_rows<T>(
+        &self,
+        table_id: &str,
+        rows: Vec<T>,
+    ) -> Result<JobReport, Box<dyn Error>>
+    where
+        T: Serialize,
+    {
+        return self.upload_rows(table_id, rows, "WRITE_APPEND", None).await;
+    }
+
+    // The table is created with the schema if it does not exist
+    pub(super) async fn upload_rows<T>(
+        &self,
+        table_id: &str,
+        rows: Vec<T>,
+        write_disposition: &str,
+        schema: Option<defs::TableSchema>,
+    ) -> Result<JobReport, Box<dyn Error>>
+    where
+        T: Serialize,
+    {
+        // A load job with no data fails
+        if rows.is_empty() {
+            return Ok(JobReport::empty());
+        }
+        let ndjson = rows
+            .iter()
+            .map(serde_json::to_string)
+            .collect::<Result<Vec<String>, serde_json::Error>>()?
+            .join("\n");
+        let job_request_body = defs::JobRequestBody {
+            configuration: defs::JobConfiguration {
+                load: Some(defs::JobConfigurationLoad {
+                    destination_table: defs::TableReference {
+                        project_id: self.project_id.to_string(),
+                        dataset_id: self.dataset_id.to_string(),
+                        table_id: table_id.to_string(),
+                    },
+                    source_format: String::from("NEWLINE_DELIMITED_JSON"),
+                    write_disposition: write_disposition.to_string(),
+                    schema,
+                }),
+                query: None,
+            },
+        };
+        // The job is created once all of the data has been uploaded,
+        // so a failed upload loads none of the rows
+        let context = format!("Load job into table {}", table_id);
+        let url = format!(
+            "{}projects/{}/jobs?uploadType=resumable",
+            UPLOAD_API_ROOT, self.project_id
+        );
+        let resp = self
+            .send(
+                self.client
+                    .post(&url)
+                    .header("X-Upload-Content-Type", "application/octet-stream")
+                    .header("X-Upload-Content-Length", ndjson.len())
+                    .json(&job_request_body),
+            )
+            .await?;
+        if !resp.status().is_success() {
+            return Err(response_to_error(resp, &context).await);
+        }
+        let session_url = match resp.headers().get(LOCATION) {
+            Some(location) => location.to_str()?.to_string(),
+            None => return Err(From::from(format!("{} returned no upload URL", context))),
+        };
+
+        let data = ndjson.into_bytes();
+        let mut resp = None;
+        for chunk in upload_chunks(data.len(), UPLOAD_CHUNK_BYTE_COUNT) {
+            let content_range = format!("bytes {}-{}/{}", chunk.start, chunk.end - 1, data.len());
+            let chunk_resp = self
+                .send(
+                    self.client
+                        .put(&session_url)
+                        .header(CONTENT_TYPE, "application/octet-stream")
+                        .header(CONTENT_RANGE, content_range)
+                        .body(data[chunk].to_vec()),
+                )
+                .await?;
+            // 308 Resume Incomplete acknowledges a chunk before the last
+            let status = chunk_resp.status();
+            if !status.is_success() && status != StatusCode::PERMANENT_REDIRECT {
+                return Err(response_to_error(chunk_resp, &context).await);
+            }
+            resp = Some(chunk_resp);
+        }
+        let resp = match resp {
+            Some(resp) if resp.status().is_success() => resp,
+            _ => return Err(From::from(format!("{} was not created", context))),
+        };
+
+        let job = self.wait_for_job(resp.json::<defs::Job>().await?).await?;
+        return Ok(job_to_job_report(job));
+    }
+
+    // Runs a GoogleSQL query in a job and waits until the job finishes
+    pub(super) async fn run_query(&self, query: &str) -> Result<JobReport, Box<dyn Error>> {
+        let job_request_body = defs::JobRequestBody {
+            configuration: defs::JobConfiguration {
+                load: None,
+                query: Some(defs::JobConfigurationQuery {
+                    query: query.to_string(),
+                    use_legacy_sql: false,
+                }),
+            },
+        };
+        let url = format!("{}projects/{}/jobs", API_ROOT, self.project_id);
+        let resp = self
+            .send(self.client.post(&url).json(&job_request_body))
+            .await?;
+        if !resp.status().is_success() {
+            return Err(response_to_error(resp, "Query job").await);
+        }
+
+        let job = self.wait_for_job(resp.json::<defs::Job>().await?).await?;
+        return Ok(job_to_job_report(job));
+    }
+
+    async fn wait_for_job(&self, job: defs::Job) -> Result<defs::Job, Box<dyn Error>> {
+        let mut job = job;
+        let mut poll_interval = INITIAL_POLL_INTERVAL;
+        while job.status.state != "DONE" {
+            tokio::time::sleep(poll_interval).await;
+            poll_interval = (poll_interval * 2).min(MAX_POLL_INTERVAL);
+
+            let mut url = format!(
+                "{}projects/{}/jobs/{}",
+                API_ROOT, job.job_reference.project_id, job.job_reference.job_id
+            );
+            // A job outside the US and EU multi-regions is not found without its location
+            if let Some(location) = &job.job_reference.location {
+                url = format!("{}?location={}", url, location);
+            }
+            let resp = self.send(self.client.get(&url)).await?;
+            if !resp.status().is_success() {
+                let context = format!("Getting job {}", job.job_reference.job_id);
+                return Err(response_to_error(resp, &context).await);
+            }
+            job = resp.json::<defs::Job>().await?;
+        }
+        return Ok(job);
+    }
+}
+
+// Splits the bytes into consecutive ranges of the chunk size, the last one taking the rest
+fn upload_chunks(byte_count: usize, chunk_byte_count: usize) -> Vec<Range<usize>> {
+    return (0..byte_count)
+        .step_by(chunk_byte_count)
+        .map(|start| start..(start + chunk_byte_count).min(byte_count))
+        .collect();
+}
+
+fn job_to_job_report(job: defs::Job) -> JobReport {
+    let affected_row_count = job
+        .statistics
+        .and_then(|statistics| {
+            let load_row_count = statistics.load.and_then(|load| load.output_rows);
+            let query_row_count = statistics
+                .query
+                .and_then(|query| query.num_dml_affected_rows);
+            load_row_count.or(query_row_count)
+        })
+        .and_then(|row_count| row_count.parse().ok())
+        .unwrap_or(0);
+    let errors = match job.status.error_result {
+        Some(error_result) => {
+            let mut errors = vec![error_proto_to_reason(&error_result)];
+            errors.extend(job.status.errors.iter().map(error_proto_to_reason));
+            errors
+        }
+        None => vec![],
+    };
+    return JobReport {
+        job_id: job.job_reference.job_id,
+        affected_row_count,
+        errors,
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn upload_chunks_covers_the_bytes() {
+        assert_eq!(upload_chunks(10, 4), vec![0..4, 4..8, 8..10]);
+        assert_eq!(upload_chunks(8, 4), vec![0..4, 4..8]);
+        assert_eq!(upload_chunks(3, 4), vec![0..3]);
+        assert!(upload_chunks(0, 4).is_empty());
+    }
+}
@@ -0,0 +1,188 @@
+use serde::Serialize;
+use std::error::Error;
+
+use crate::bq_client::{response_to_error, schema, BqClient, JobReport, API_ROOT};
+use crate::converter::{MergeRule, TableSchema};
+
+// The rows are staged in this table before being merged into the target table
+const STAGING_TABLE_SUFFIX: &str = "_staging";
+
+impl BqClient {
+    // Upserts the rows into the table: a row replaces the existing row with the same key,
+    // and is inserted if there is none. The rows must be unique on the key column.
+    pub async fn merge_rows<T>(
+        &self,
+        table_id: &str,
+        rows: Vec<T>,
+        key_column: &str,
+    ) -> Result<JobReport, Box<dyn Error>>
+    where
+        T: Serialize + TableSchema,
+    {
+        if rows.is_empty() {
+            return Ok(JobReport::empty());
+        }
+        let columns = T::columns();
+        let staging_table_id = format!("{}{}", table_id, STAGING_TABLE_SUFFIX);
+        // Truncate the staging table in case a failed run left it behind
+        let load_report = self
+            .upload_rows(
+                &staging_table_id,
+                rows,
+                "WRITE_TRUNCATE",
+                Some(super::defs::TableSchema {
+                    fields: schema::columns_to_fields(&columns),
+                }),
+            )
+            .await?;
+        if !load_report.is_success() {
+            return Ok(load_report);
+        }
+
+        let column_names: Vec<&str> = columns.iter().map(|column| column.name).collect();
+        let query = merge_statement(
+            &self.table_path(table_id),
+            &self.table_path(&staging_table_id),
+            &column_names,
+            key_column,
+            T::merge_rule,
+        );
+        let merge_report = self.run_query(&query).await?;
+        if merge_report.is_success() {
+            self.delete_table(&staging_table_id).await?;
+        }
+        return Ok(merge_report);
+    }
+
+    // Deletes every version whose content columns are the same as the previous version of the same key,
+    // e.g. one written by a later run for a track added to another playlist
+    pub async fn remove_unchanged_versions(
+        &self,
+        table_id: &str,
+        key_column: &str,
+        content_columns: &[&str],
+    ) -> Result<JobReport, Box<dyn Error>> {
+        // TO_JSON_STRING makes the arrays comparable
+        let conditions = content_columns
+            .iter()
+            .map(|column| format!("TO_JSON_STRING(T.{c}) = TO_JSON_STRING(P.{c})", c = column))
+            .collect::<Vec<String>>()
+            .join(" AND ");
+        let query = format!(
+            "DELETE FROM {table} T WHERE EXISTS (\
+             SELECT 1 FROM (SELECT {key}, valid_from, \
+             LAG(valid_from) OVER (PARTITION BY {key} ORDER BY valid_from) AS previous_valid_from \
+             FROM {table}) N JOIN {table} P ON P.{key} = N.{key} AND P.valid_from = N.previous_valid_from \
+             WHERE N.{key} = T.{key} AND N.valid_from = T.valid_from AND {conditions})",
+            table = self.table_path(table_id),
+            key = key_column,
+            conditions = conditions
+        );
+        return self.run_query(&query).await;
+    }
+
+    // Sets valid_to of every version to valid_from of the next version of the same key,
+    // closing the versions written by earlier runs that have been superseded since
+    pub async fn close_superseded_versions(
+        &self,
+        table_id: &str,
+        key_column: &str,
+    ) -> Result<JobReport, Box<dyn Error>> {
+        let query = format!(
+            "UPDATE {table} T SET valid_to = N.next_valid_from \
+             FROM (SELECT {key}, valid_from, \
+             LEAD(valid_from) OVER (PARTITION BY {key} ORDER BY valid_from) AS next_valid_from \
+             FROM {table}) N \
+             WHERE T.{key} = N.{key} AND T.valid_from = N.valid_from \
+             AND T.valid_to IS DISTINCT FROM N.next_valid_from",
+            table = self.table_path(table_id),
+            key = key_column
+        );
+        return self.run_query(&query).await;
+    }
+
+    // Removes every row of the table, keeping its schema
+    pub async fn truncate_table(&self, table_id: &str) -> Result<JobReport, Box<dyn Error>> {
+        return self
+            .run_query(&format!("TRUNCATE TABLE {}", self.table_path(table_id)))
+            .await;
+    }
+
+    async fn delete_table(&self, table_id: &str) -> Result<(), Box<dyn Error>> {
+        let url = format!(
+            "{}projects/{}/datasets/{}/tables/{}",
+            API_ROOT, self.project_id, self.dataset_id, table_id
+        );
+        let resp = self.send(self.client.delete(&url)).await?;
+        if !resp.status().is_success() {
+            return Err(response_to_error(resp, &format!("Deleting table {}", table_id)).await);
+        }
+        return Ok(());
+    }
+
+    // The fully qualified table name quoted for GoogleSQL
+    fn table_path(&self, table_id: &str) -> String {
+        return format!("`{}.{}.{}`", self.project_id, self.dataset_id, table_id);
+    }
+}
+
+fn merge_statement(
+    target_table: &str,
+    source_table: &str,
+    column_names: &[&str],
+    key_column: &str,
+    merge_rule: fn(&str) -> MergeRule,
+) -> String {
+    let updates = column_names
+        .iter()
+        .filter(|column_name| **column_name != key_column)
+        .map(|column_name| match merge_rule(column_name) {
+            MergeRule::Replace => format!("{c} = S.{c}", c = column_name),
+            MergeRule::KeepMin => format!("{c} = LEAST(T.{c}, S.{c})", c = column_name),
+            MergeRule::KeepMax => format!("{c} = GREATEST(T.{c}, S.{c})", c = column_name),
+        })
+        .collect::<Vec<String>>()
+        .join(", ");
+    let values = column_names
+        .iter()
+        .map(|column_name| format!("S.{}", column_name))
+        .collect::<Vec<String>>()
+        .join(", ");
+    return format!(
+        "MERGE {target} T USING {source} S ON T.{key} = S.{key} \
+         WHEN MATCHED THEN UPDATE SET {updates} \
+         WHEN NOT MATCHED THEN INSERT ({columns}) VALUES ({values})",
+        target = target_table,
+        source = source_table,
+        key = key_column,
+        updates = updates,
+        columns = column_names.join(", "),
+        values = values
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::converter::PlaylistTableRow;
+
+    #[test]
+    fn merge_statement_updates_the_columns_by_their_rules() {
+        let statement = merge_statement(
+            "`p.d.playlists`",
+            "`p.d.playlists_staging`",
+            &["id", "name", "first_seen_at", "last_seen_at"],
+            "id",
+            PlaylistTableRow::merge_rule,
+        );
+        assert_eq!(
+            statement,
+            "MERGE `p.d.playlists` T USING `p.d.playlists_staging` S ON T.id = S.id \
+             WHEN MATCHED THEN UPDATE SET name = S.name, \
+             first_seen_at = LEAST(T.first_seen_at, S.first_seen_at), \
+             last_seen_at = GREATEST(T.last_seen_at, S.last_seen_at) \
+             WHEN NOT MATCHED THEN INSERT (id, name, first_seen_at, last_seen_at) \
+             VALUES (S.id, S.name, S.first_seen_at, S.last_seen_at)"
+        );
+    }
+}
@@ -1,56 +1,411 @@
-use reqwest;
-use reqwest::header::AUTHORIZATION;
+use futures::stream::{self, StreamExt};
+use reqwest::header::{AUTHORIZATION, CONTENT_TYPE};
+use reqwest::StatusCode;
 use serde::Serialize;
-use serde_json;
+use std::error::Error;
+use std::ops::Range;
+use std::str::FromStr;
 
+use crate::converter::{RowKey, TableSchema};
+
+pub mod auth;
+mod batch;
 mod defs;
+mod job;
+mod merge;
+pub mod schema;
+
+pub use job::JobReport;
 
 pub struct BqClient {
     client: reqwest::Client,
-    access_token: String,
+    authenticator: auth::Authenticator,
     project_id: String,
     dataset_id: String,
+    dataset_location: Option<String>, // Only used when creating the dataset
+    insert_limits: InsertLimits,
+}
+
+// How the rows are written into the tables
+#[derive(Debug, Clone, Copy)]
+pub enum WriteMode {
+    // insertAll; the rows are queryable right away, but billed and stuck in the streaming buffer for a while
+    Streaming,
+    // Free batch load jobs, suited to large backfills
+    LoadJob,
+    // Load jobs for the action table, and MERGE statements keyed on the ID
+    // for the track and artist tables so that each track and artist appears once
+    Merge,
+}
+
+impl FromStr for WriteMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        return match s {
+            "streaming" => Ok(WriteMode::Streaming),
+            "load" => Ok(WriteMode::LoadJob),
+            "merge" => Ok(WriteMode::Merge),
+            _ => Err(format!(
+                "Unknown write mode {}; expected streaming, load or merge",
+                s
+            )),
+        };
+    }
+}
+
+pub enum TableStatus {
+    Created,
+    Exists {
+        added_columns: Vec<String>, // The missing NULLABLE and REPEATED columns, added to the table
+        drifts: Vec<schema::SchemaDrift>, // The remaining differences; empty when the schema matches the row type
+    },
+}
+
+// How insert_rows splits the rows into insertAll requests
+#[derive(Debug, Clone, Copy)]
+pub struct InsertLimits {
+    pub max_batch_rows: usize,
+    pub max_batch_bytes: usize,
+    pub max_concurrent_requests: usize,
+}
+
+impl Default for InsertLimits {
+    fn default() -> Self {
+        // The API recommends up to 500 rows per request and rejects requests over 10 MB
+        return InsertLimits {
+            max_batch_rows: 500,
+            max_batch_bytes: 9 * 1024 * 1024,
+            max_concurrent_requests: 4,
+        };
+    }
+}
+
+// The outcome of the insertAll requests, whose rows were accepted or rejected individually,
+// or all together when a request failed
+#[derive(Debug)]
+pub struct InsertRowsReport {
+    pub inserted_row_count: usize,
+    pub failed_rows: Vec<FailedRow>,
+    pub failed_batches: Vec<FailedBatch>,
+}
+
+#[derive(Debug)]
+pub struct FailedRow {
+    pub index: usize, // Index in the rows passed to insert_rows
+    pub reasons: Vec<String>,
+}
+
+#[derive(Debug)]
+pub struct FailedBatch {
+    pub rows: Range<usize>, // Indexes in the rows passed to insert_rows
+    pub reason: String,
+}
+
+impl InsertRowsReport {
+    pub fn is_success(&self) -> bool {
+        return self.failed_rows.is_empty() && self.failed_batches.is_empty();
+    }
 }
 
 const API_ROOT: &str = "https://bigquery.googleapis.com/bigquery/v2/";
 
 impl BqClient {
+    // Creates the dataset unless it exists. Returns whether it was created.
+    pub async fn ensure_dataset(&self) -> Result<bool, Box<dyn Error>> {
+        let dataset_path = format!("projects/{}/datasets/{}", self.project_id, self.dataset_id);
+        let resp = self
+            .send(self.client.get(format!("{}{}", API_ROOT, dataset_path)))
+            .await?;
+        if resp.status().is_success() {
+            return Ok(false);
+        }
+        if resp.status() != StatusCode::NOT_FOUND {
+            return Err(
+                response_to_error(resp, &format!("Getting dataset {}", self.dataset_id)).await,
+            );
+        }
+
+        let request_body = defs::Dataset {
+            dataset_reference: defs::DatasetReference {
+                project_id: self.project_id.to_string(),
+                dataset_id: self.dataset_id.to_string(),
+            },
+            location: self.dataset_location.clone(),
+        };
+        let datasets_path = format!("projects/{}/datasets", self.project_id);
+        let resp = self
+            .send(
+                self.client
+                    .post(format!("{}{}", API_ROOT, datasets_path))
+                    .json(&request_body),
+            )
+            .await?;
+        if !resp.status().is_success() {
+            return Err(
+                response_to_error(resp, &format!("Creating dataset {}", self.dataset_id)).await,
+            );
+        }
+        return Ok(true);
+    }
+
+    // Creates the table with the schema of the row type unless it exists.
+    // Otherwise, adds the missing columns that can be added to the existing table,
+    // and compares the rest of its schema with the row type.
+    pub async fn ensure_table<T>(&self, table_id: &str) -> Result<TableStatus, Box<dyn Error>>
+    where
+        T: TableSchema,
+    {
+        let expected_fields = schema::columns_to_fields(&T::columns());
+        let table_path = format!(
+            "projects/{}/datasets/{}/tables/{}",
+            self.project_id, self.dataset_id, table_id
+        );
+        let resp = self
+            .send(self.client.get(format!("{}{}", API_ROOT, table_path)))
+            .await?;
+        if resp.status().is_success() {
+            let mut table = resp.json::<defs::Table>().await?;
+            let drifts = schema::find_schema_drifts(&expected_fields, &table.schema.fields);
+            let added_fields = schema::addable_fields(&expected_fields, &drifts);
+            if added_fields.is_empty() {
+                return Ok(TableStatus::Exists {
+                    added_columns: vec![],
+                    drifts,
+                });
+            }
+
+            let added_columns: Vec<String> = added_fields
+                .iter()
+                .map(|field| field.name.to_string())
+                .collect();
+            // The new schema has to keep the existing columns
+            table.schema.fields.extend(added_fields);
+            let resp = self
+                .send(
+                    self.client
+                        .patch(format!("{}{}", API_ROOT, table_path))
+                        .json(&table),
+                )
+                .await?;
+            if !resp.status().is_success() {
+                return Err(response_to_error(
+                    resp,
+                    &format!(
+                        "Adding columns {} to table {}",
+                        added_columns.join(", "),
+                        table_id
+                    ),
+                )
+                .await);
+            }
+            let drifts = drifts
+                .into_iter()
+                .filter(|drift| match drift {
+                    schema::SchemaDrift::MissingColumn(column) => !added_columns.contains(column),
+                    _ => true,
+                })
+                .collect();
+            return Ok(TableStatus::Exists {
+                added_columns,
+                drifts,
+            });
+        }
+        if resp.status() != StatusCode::NOT_FOUND {
+            return Err(response_to_error(resp, &format!("Getting table {}", table_id)).await);
+        }
+
+        let request_body = defs::Table {
+            table_reference: defs::TableReference {
+                project_id: self.project_id.to_string(),
+                dataset_id: self.dataset_id.to_string(),
+                table_id: table_id.to_string(),
+            },
+            schema: defs::TableSchema {
+                fields: expected_fields,
+            },
+        };
+        let tables_path = format!(
+            "projects/{}/datasets/{}/tables",
+            self.project_id, self.dataset_id
+        );
+        let resp = self
+            .send(
+                self.client
+                    .post(format!("{}{}", API_ROOT, tables_path))
+                    .json(&request_body),
+            )
+            .await?;
+        if !resp.status().is_success() {
+            return Err(response_to_error(resp, &format!("Creating table {}", table_id)).await);
+        }
+        return Ok(TableStatus::Created);
+    }
+
+    // Sends the rows in batches within the insert limits.
+    // A failed request does not stop the other batches; the report has the rows of every batch
+    // that failed, as well as the rows rejected individually.
     pub async fn insert_rows<T>(
         &self,
         table_id: &str,
         rows: Vec<T>,
-    ) -> Result<(), Box<dyn std::error::Error>>
+    ) -> Result<InsertRowsReport, Box<dyn Error>>
     where
-        T: Serialize,
+        T: Serialize + RowKey,
     {
         let path = format!(
             "projects/{}/datasets/{}/tables/{}/insertAll",
             self.project_id, self.dataset_id, table_id
         );
         let url = format!("{}{}", API_ROOT, path);
-        let request_body = defs::InsertRowsRequestBody {
-            rows: rows
-                .into_iter()
-                .map(|row| defs::InsertRowsRequestBodyRow { json: row })
-                .collect(),
+        let serialized_rows = rows
+            .into_iter()
+            .map(|row| {
+                serde_json::to_string(&defs::InsertRowsRequestBodyRow {
+                    insert_id: row.row_key(),
+                    json: row,
+                })
+            })
+            .collect::<Result<Vec<String>, serde_json::Error>>()?;
+        let batches = batch::split_into_batches(
+            serialized_rows,
+            self.insert_limits.max_batch_rows,
+            self.insert_limits.max_batch_bytes,
+        );
+
+        let url = &url;
+        let results = stream::iter(batches.iter())
+            .map(|batch| async move {
+                let result = self.insert_batch(url, table_id, batch).await;
+                return (batch, result);
+            })
+            .buffer_unordered(self.insert_limits.max_concurrent_requests.max(1))
+            .collect::<Vec<(&batch::Batch, Result<InsertRowsReport, Box<dyn Error>>)>>()
+            .await;
+
+        let mut report = InsertRowsReport {
+            inserted_row_count: 0,
+            failed_rows: vec![],
+            failed_batches: vec![],
         };
+        for (batch, result) in results {
+            match result {
+                Ok(batch_report) => {
+                    report.inserted_row_count += batch_report.inserted_row_count;
+                    report.failed_rows.extend(batch_report.failed_rows);
+                }
+                Err(e) => report.failed_batches.push(FailedBatch {
+                    rows: batch.first_index..batch.first_index + batch.rows.len(),
+                    reason: e.to_string(),
+                }),
+            }
+        }
+        report
+            .failed_rows
+            .sort_by_key(|failed_row| failed_row.index);
+        report
+            .failed_batches
+            .sort_by_key(|failed_batch| failed_batch.rows.start);
+        return Ok(report);
+    }
+
+    async fn insert_batch(
+        &self,
+        url: &str,
+        table_id: &str,
+        batch: &batch::Batch,
+    ) -> Result<InsertRowsReport, Box<dyn Error>> {
         let resp = self
-            .client
-            .post(&url)
-            .header(AUTHORIZATION, format!("Bearer {}", self.access_token))
-            .body(serde_json::to_string(&request_body)?)
+            .send(
+                self.client
+                    .post(url)
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(batch.to_request_body()),
+            )
+            .await?;
+        if !resp.status().is_success() {
+            let context = format!(
+                "insertAll of rows {}..{} into table {}",
+                batch.first_index,
+                batch.first_index + batch.rows.len(),
+                table_id
+            );
+            return Err(response_to_error(resp, &context).await);
+        }
+
+        let response_body = resp.json::<defs::InsertRowsResponseBody>().await?;
+        let failed_rows: Vec<FailedRow> = response_body
+            .insert_errors
+            .into_iter()
+            .map(|insert_error| FailedRow {
+                // The API counts the rows from the start of the batch
+                index: batch.first_index + insert_error.index,
+                reasons: insert_error
+                    .errors
+                    .iter()
+                    .map(error_proto_to_reason)
+                    .collect(),
+            })
+            .collect();
+        return Ok(InsertRowsReport {
+            inserted_row_count: batch.rows.len() - failed_rows.len(),
+            failed_rows,
+            failed_batches: vec![],
+        });
+    }
+}
+
+impl BqClient {
+    async fn send(
+        &self,
+        request: reqwest::RequestBuilder,
+    ) -> Result<reqwest::Response, Box<dyn Error>> {
+        let access_token = self.authenticator.access_token(&self.client).await?;
+        let resp = request
+            .header(AUTHORIZATION, format!("Bearer {}", access_token))
             .send()
             .await?;
-        println!("{:#?}", resp);
-        return Ok(());
+        return Ok(resp);
+    }
+}
+
+// Builds an error from a non-success response, preferring the message in the error body
+async fn response_to_error(resp: reqwest::Response, context: &str) -> Box<dyn Error> {
+    let status = resp.status();
+    let body = match resp.text().await {
+        Ok(body) => body,
+        Err(e) => return Box::new(e),
+    };
+    let message = match serde_json::from_str::<defs::ErrorResponseBody>(&body) {
+        Ok(error_body) => error_body.error.message,
+        Err(_e) => body,
+    };
+    return From::from(format!(
+        "{} failed with status {}: {}",
+        context, status, message
+    ));
+}
+
+fn error_proto_to_reason(error: &defs::ErrorProto) -> String {
+    if error.location.is_empty() {
+        return format!("{}: {}", error.reason, error.message);
     }
+    return format!("{}: {} ({})", error.reason, error.message, error.location);
 }
 
-pub fn new(access_token: &str, project_id: &str, dataset_id: &str) -> BqClient {
+pub fn new(
+    credentials: auth::Credentials,
+    project_id: &str,
+    dataset_id: &str,
+    dataset_location: Option<String>,
+    insert_limits: InsertLimits,
+) -> BqClient {
     return BqClient {
         client: reqwest::Client::new(),
-        access_token: access_token.to_string(),
+        authenticator: auth::new(credentials),
         project_id: project_id.to_string(),
         dataset_id: dataset_id.to_string(),
+        dataset_location,
+        insert_limits,
     };
 }
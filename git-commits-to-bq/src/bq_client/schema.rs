@@ -0,0 +1,228 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::bq_client::defs::TableFieldSchema;
+use crate::converter::{Column, ColumnMode, ColumnType};
+
+// A difference between the schema of an existing table and the one derived from the row type
+#[derive(Debug)]
+pub enum SchemaDrift {
+    MissingColumn(String),
+    UnexpectedColumn(String),
+    TypeMismatch {
+        column: String,
+        expected: String,
+        actual: String,
+    },
+    ModeMismatch {
+        column: String,
+        expected: String,
+        actual: String,
+    },
+}
+
+impl fmt::Display for SchemaDrift {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return match self {
+            SchemaDrift::MissingColumn(column) => {
+                write!(f, "column {} is missing from the table", column)
+            }
+            SchemaDrift::UnexpectedColumn(column) => {
+                write!(f, "column {} is not written by the program", column)
+            }
+            SchemaDrift::TypeMismatch {
+                column,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "column {} has type {} instead of {}",
+                column, actual, expected
+            ),
+            SchemaDrift::ModeMismatch {
+                column,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "column {} has mode {} instead of {}",
+                column, actual, expected
+            ),
+        };
+    }
+}
+
+impl SchemaDrift {
+    // Whether writing the rows into the table fails because of the drift
+    pub fn breaks_writes(&self) -> bool {
+        return match self {
+            SchemaDrift::UnexpectedColumn(_) => false,
+            // A NULLABLE column takes the values of a REQUIRED one
+            SchemaDrift::ModeMismatch {
+                expected, actual, ..
+            } => !(expected == "REQUIRED" && actual == "NULLABLE"),
+            SchemaDrift::MissingColumn(_) | SchemaDrift::TypeMismatch { .. } => true,
+        };
+    }
+}
+
+pub fn columns_to_fields(columns: &[Column]) -> Vec<TableFieldSchema> {
+    return columns
+        .iter()
+        .map(|column| TableFieldSchema {
+            name: column.name.to_string(),
+            field_type: column_type_to_field_type(column.column_type).to_string(),
+            mode: column_mode_to_field_mode(column.mode).to_string(),
+        })
+        .collect();
+}
+
+pub fn find_schema_drifts(
+    expected_fields: &[TableFieldSchema],
+    actual_fields: &[TableFieldSchema],
+) -> Vec<SchemaDrift> {
+    let mut name_to_actual_field = HashMap::new();
+    for field in actual_fields {
+        name_to_actual_field.insert(&field.name, field);
+    }
+    let mut drifts = vec![];
+    for expected_field in expected_fields {
+        let actual_field = match name_to_actual_field.remove(&expected_field.name) {
+            Some(actual_field) => actual_field,
+            None => {
+                drifts.push(SchemaDrift::MissingColumn(expected_field.name.to_string()));
+                continue;
+            }
+        };
+        if actual_field.field_type != expected_field.field_type {
+            drifts.push(SchemaDrift::TypeMismatch {
+                column: expected_field.name.to_string(),
+                expected: expected_field.field_type.to_string(),
+                actual: actual_field.field_type.to_string(),
+            });
+        }
+        if actual_field.mode != expected_field.mode {
+            drifts.push(SchemaDrift::ModeMismatch {
+                column: expected_field.name.to_string(),
+                expected: expected_field.mode.to_string(),
+                actual: actual_field.mode.to_string(),
+            });
+        }
+    }
+    // Report the remaining columns in the order of the table schema
+    for actual_field in actual_fields {
+        if name_to_actual_field.contains_key(&actual_field.name) {
+            drifts.push(SchemaDrift::UnexpectedColumn(actual_field.name.to_string()));
+        }
+    }
+    return drifts;
+}
+
+// The expected fields missing from the table that can be added to it.
+// BigQuery only adds NULLABLE and REPEATED columns to an existing table.
+pub fn addable_fields(
+    expected_fields: &[TableFieldSchema],
+    drifts: &[SchemaDrift],
+) -> Vec<TableFieldSchema> {
+    return expected_fields
+        .iter()
+        .filter(|field| field.mode != "REQUIRED")
+        .filter(|field| {
+            drifts.iter().any(|drift| match drift {
+                SchemaDrift::MissingColumn(column) => *column == field.name,
+                _ => false,
+            })
+        })
+        .cloned()
+        .collect();
+}
+
+fn column_type_to_field_type(column_type: ColumnType) -> &'static str {
+    return match column_type {
+        ColumnType::String => "STRING",
+        ColumnType::Integer => "INTEGER",
+        ColumnType::Timestamp => "TIMESTAMP",
+    };
+}
+
+fn column_mode_to_field_mode(mode: ColumnMode) -> &'static str {
+    return match mode {
+        ColumnMode::Required => "REQUIRED",
+        ColumnMode::Nullable => "NULLABLE",
+        ColumnMode::Repeated => "REPEATED",
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn field(name: &str, field_type: &str, mode: &str) -> TableFieldSchema {
+        return TableFieldSchema {
+            name: name.to_string(),
+            field_type: field_type.to_string(),
+            mode: mode.to_string(),
+        };
+    }
+
+    fn descriptions(drifts: &[SchemaDrift]) -> Vec<String> {
+        return drifts.iter().map(|drift| drift.to_string()).collect();
+    }
+
+    #[test]
+    fn find_schema_drifts_of_the_same_schema_is_empty() {
+        let fields = vec![
+            field("id", "STRING", "REQUIRED"),
+            field("name", "STRING", "NULLABLE"),
+        ];
+        assert!(find_schema_drifts(&fields, &fields).is_empty());
+    }
+
+    #[test]
+    fn find_schema_drifts_reports_every_difference() {
+        let expected_fields = vec![
+            field("id", "STRING", "REQUIRED"),
+            field("name", "STRING", "REQUIRED"),
+            field("count", "INTEGER", "NULLABLE"),
+            field("added_at", "TIMESTAMP", "NULLABLE"),
+        ];
+        let actual_fields = vec![
+            field("extra", "STRING", "NULLABLE"),
+            field("id", "STRING", "REQUIRED"),
+            field("name", "STRING", "NULLABLE"),
+            field("count", "STRING", "NULLABLE"),
+        ];
+
+        let drifts = find_schema_drifts(&expected_fields, &actual_fields);
+
+        assert_eq!(
+            descriptions(&drifts),
+            vec![
+                "column name has mode NULLABLE instead of REQUIRED",
+                "column count has type STRING instead of INTEGER",
+                "column added_at is missing from the table",
+                "column extra is not written by the program",
+            ]
+        );
+        let breaking_drifts: Vec<bool> = drifts.iter().map(|drift| drift.breaks_writes()).collect();
+        assert_eq!(breaking_drifts, vec![false, true, true, false]);
+    }
+
+    #[test]
+    fn addable_fields_are_the_missing_non_required_fields() {
+        let expected_fields = vec![
+            field("id", "STRING", "REQUIRED"),
+            field("artist_ids", "STRING", "REPEATED"),
+            field("added_at", "TIMESTAMP", "NULLABLE"),
+        ];
+        let actual_fields = vec![field("added_at", "TIMESTAMP", "NULLABLE")];
+
+        let drifts = find_schema_drifts(&expected_fields, &actual_fields);
+        let names: Vec<String> = addable_fields(&expected_fields, &drifts)
+            .into_iter()
+            .map(|field| field.name)
+            .collect();
+
+        assert_eq!(names, vec!["artist_ids"]);
+    }
+}
@@ -0,0 +1,58 @@
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs;
+use std::io::ErrorKind;
+
+// The last commit whose actions have been loaded into the tables
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub sha: String,
+    pub datetime: DateTime<Utc>,
+}
+
+// Where the checkpoint is kept between runs
+pub trait CheckpointStore {
+    // Returns None when no sync has been completed yet
+    fn load(&self) -> Result<Option<Checkpoint>, Box<dyn Error>>;
+
+    fn save(&self, checkpoint: &Checkpoint) -> Result<(), Box<dyn Error>>;
+}
+
+// A JSON file, used unless the sink keeps the checkpoint itself
+pub struct CheckpointFile {
+    path: String,
+}
+
+impl CheckpointStore for CheckpointFile {
+    fn load(&self) -> Result<Option<Checkpoint>, Box<dyn Error>> {
+        let content = match fs::read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(Box::new(e)),
+        };
+        let checkpoint = serde_json::from_str(&content)?;
+        return Ok(Some(checkpoint));
+    }
+
+    fn save(&self, checkpoint: &Checkpoint) -> Result<(), Box<dyn Error>> {
+        fs::write(&self.path, serde_json::to_string_pretty(checkpoint)?)?;
+        return Ok(());
+    }
+}
+
+impl CheckpointFile {
+    pub fn remove(&self) -> Result<(), Box<dyn Error>> {
+        return match fs::remove_file(&self.path) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(Box::new(e)),
+        };
+    }
+}
+
+pub fn file(path: &str) -> CheckpointFile {
+    return CheckpointFile {
+        path: path.to_string(),
+    };
+}
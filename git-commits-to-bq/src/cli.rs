@@ -0,0 +1,402 @@
+use chrono::prelude::*;
+use clap::{Args, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use std::env;
+
+use crate::bq_client;
+use crate::github_client;
+use crate::spotify_log;
+
+// Every option can also be given by the environment variable of the same name in upper snake case,
+// which the option overrides
+#[derive(Parser)]
+#[command(
+    version,
+    about = "Converts the Spotify log committed to the spotify-backup repository into tables",
+    args_conflicts_with_subcommands = true
+)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    // Running without a subcommand is the same as `sync`
+    #[command(flatten)]
+    pub sync: SyncArgs,
+}
+
+// The arguments are parsed once, so the sizes of the variants do not matter
+#[allow(clippy::large_enum_variant)]
+#[derive(Subcommand)]
+pub enum Command {
+    #[command(about = "Fetch the new commits, convert them and write the rows into the sink")]
+    Sync(SyncArgs),
+    #[command(about = "Dump the raw commits into the local commit cache")]
+    Fetch {
+        #[command(flatten)]
+        source: SourceArgs,
+        #[command(flatten)]
+        cache: CacheArgs,
+    },
+    #[command(about = "Convert the cached commits after the checkpoint into row files")]
+    Convert {
+        #[command(flatten)]
+        cache: CacheArgs,
+        #[command(flatten)]
+        rows: RowsArgs,
+        // The sink the rows are loaded into, which may keep the checkpoint
+        #[command(flatten)]
+        sink: SinkArgs,
+        #[command(flatten)]
+        checkpoint: CheckpointArgs,
+        #[command(flatten)]
+        report: ReportArgs,
+        #[command(flatten)]
+        log: LogArgs,
+        #[arg(
+            long,
+            help = "Convert every cached commit regardless of the checkpoint"
+        )]
+        full_resync: bool,
+    },
+    #[command(about = "Write the row files into the sink and move the checkpoint")]
+    Load {
+        #[command(flatten)]
+        rows: RowsArgs,
+        #[command(flatten)]
+        sink: SinkArgs,
+        #[command(flatten)]
+        checkpoint: CheckpointArgs,
+    },
+    #[command(about = "Show how one commit is classified and diffed")]
+    Inspect {
+        #[arg(help = "The SHA of the commit")]
+        sha: String,
+        #[command(flatten)]
+        source: SourceArgs,
+    },
+    #[command(about = "Print the schemas of the tables")]
+    Schema {
+        #[arg(
+            long,
+            help = "Print the BigQuery JSON schemas, e.g. for `bq mk --schema`"
+        )]
+        json: bool,
+    },
+}
+
+#[derive(Args)]
+pub struct SyncArgs {
+    #[command(flatten)]
+    pub source: SourceArgs,
+    #[command(flatten)]
+    pub sink: SinkArgs,
+    #[command(flatten)]
+    pub checkpoint: CheckpointArgs,
+    #[command(flatten)]
+    pub report: ReportArgs,
+    #[arg(
+        long,
+        help = "Sync from the beginning of the history, ignoring the checkpoint"
+    )]
+    pub full_resync: bool,
+    #[arg(
+        long,
+        env = "CONFIG_PATH",
+        help = "A TOML file of the repositories to sync, each into its own tables"
+    )]
+    pub config_path: Option<String>,
+}
+
+#[derive(Args)]
+#[command(next_help_heading = "Source")]
+pub struct SourceArgs {
+    #[arg(
+        long,
+        env = "LOCAL_REPO_PATH",
+        help = "Read the commits from this local clone instead of the GitHub API"
+    )]
+    pub local_repo_path: Option<String>,
+    #[arg(
+        long,
+        env = "GITHUB_API_URL",
+        default_value = github_client::DEFAULT_API_URL,
+        help = "The base URL of the GitHub API, e.g. of a GitHub Enterprise Server"
+    )]
+    pub github_api_url: String,
+    #[arg(
+        long,
+        env = "GITHUB_TOKEN",
+        hide_env_values = true,
+        help = "A GitHub token"
+    )]
+    pub github_token: Option<String>,
+    #[arg(
+        long,
+        env = "REPO_OWNER",
+        help = "The owner of the spotify-backup repository"
+    )]
+    pub repo_owner: Option<String>,
+    #[arg(
+        long,
+        env = "REPO_NAME",
+        help = "The name of the spotify-backup repository"
+    )]
+    pub repo_name: Option<String>,
+    #[arg(
+        long,
+        env = "GITHUB_COMMITS_SINCE",
+        help = "Only list the commits at or after this time, e.g. 2020-01-01T00:00:00Z"
+    )]
+    pub github_commits_since: Option<DateTime<Utc>>,
+    #[arg(
+        long,
+        env = "GITHUB_COMMITS_UNTIL",
+        help = "Only list the commits at or before this time"
+    )]
+    pub github_commits_until: Option<DateTime<Utc>>,
+    #[arg(
+        long,
+        env = "GITHUB_BRANCH",
+        help = "Read the commits of this branch instead of the default one, also from a local clone"
+    )]
+    pub github_branch: Option<String>,
+    #[command(flatten)]
+    pub log: LogArgs,
+    #[arg(
+        long,
+        env = "FETCH_CONCURRENCY",
+        default_value_t = 4,
+        help = "The number of commits fetched at once"
+    )]
+    pub fetch_concurrency: usize,
+    #[arg(
+        long,
+        env = "DOWNLOAD_CONCURRENCY",
+        default_value_t = 8,
+        help = "The number of raw snapshot files downloaded from GitHub at once"
+    )]
+    pub download_concurrency: usize,
+}
+
+// Which commits of the repository are the log
+#[derive(Args)]
+#[command(next_help_heading = "Source")]
+pub struct LogArgs {
+    #[arg(
+        long,
+        env = "LOG_COMMITTER_NAME",
+        default_value = spotify_log::DEFAULT_LOG_COMMITTER_NAME,
+        help = "The committer of the backup job; the other commits are skipped"
+    )]
+    pub log_committer_name: String,
+    #[arg(
+        long,
+        env = "LOG_STARTED_AT",
+        default_value = spotify_log::DEFAULT_LOG_STARTED_AT,
+        help = "When the backup job started to work; the earlier commits are skipped"
+    )]
+    pub log_started_at: DateTime<Utc>,
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+pub enum SinkKind {
+    Bigquery,
+    Ndjson,
+    Csv,
+    Parquet,
+    Sqlite,
+}
+
+#[derive(Args)]
+#[command(next_help_heading = "Sink")]
+pub struct SinkArgs {
+    #[arg(
+        long,
+        env = "SINK",
+        value_enum,
+        default_value = "bigquery",
+        help = "Where the rows are written"
+    )]
+    pub sink: SinkKind,
+    #[arg(
+        long,
+        env = "OUTPUT_DIR",
+        default_value = "output",
+        help = "The directory of the ndjson, csv and parquet sinks"
+    )]
+    pub output_dir: String,
+    #[arg(
+        long,
+        env = "SQLITE_PATH",
+        default_value = "spotify_log.sqlite",
+        help = "The database file of the sqlite sink"
+    )]
+    pub sqlite_path: String,
+    #[arg(
+        long,
+        env = "PARQUET_PARTITION_BY_MONTH",
+        help = "Split the actions by month in the parquet sink"
+    )]
+    pub parquet_partition_by_month: bool,
+    #[command(flatten)]
+    pub bigquery: BigQueryArgs,
+}
+
+#[derive(Args)]
+#[command(next_help_heading = "BigQuery sink")]
+pub struct BigQueryArgs {
+    #[arg(
+        long,
+        env = "GCP_SERVICE_ACCOUNT_KEY_PATH",
+        help = "A service account JSON key, preferred over an access token"
+    )]
+    pub gcp_service_account_key_path: Option<String>,
+    #[arg(
+        long,
+        env = "GCP_TOKEN_URI",
+        help = "Overrides the token endpoint in the service account key"
+    )]
+    pub gcp_token_uri: Option<String>,
+    #[arg(
+        long,
+        env = "GCP_ACCESS_TOKEN",
+        hide_env_values = true,
+        help = "An access token, e.g. from gcloud; it is never refreshed"
+    )]
+    pub gcp_access_token: Option<String>,
+    #[arg(long, env = "BQ_PROJECT_ID", help = "The project of the dataset")]
+    pub bq_project_id: Option<String>,
+    #[arg(long, env = "BQ_DATASET_ID", help = "The dataset of the tables")]
+    pub bq_dataset_id: Option<String>,
+    #[arg(
+        long,
+        env = "BQ_DATASET_LOCATION",
+        help = "Where the dataset is created, e.g. US"
+    )]
+    pub bq_dataset_location: Option<String>,
+    #[arg(long, env = "BQ_ACTION_TABLE_ID", help = "The action table")]
+    pub bq_action_table_id: Option<String>,
+    #[arg(long, env = "BQ_TRACK_TABLE_ID", help = "The track table")]
+    pub bq_track_table_id: Option<String>,
+    #[arg(long, env = "BQ_ARTIST_TABLE_ID", help = "The artist table")]
+    pub bq_artist_table_id: Option<String>,
+    #[arg(
+        long,
+        env = "BQ_TRACK_HISTORY_TABLE_ID",
+        help = "The optional track history table"
+    )]
+    pub bq_track_history_table_id: Option<String>,
+    #[arg(
+        long,
+        env = "BQ_PLAYLIST_ACTION_TABLE_ID",
+        help = "The optional playlist action table"
+    )]
+    pub bq_playlist_action_table_id: Option<String>,
+    #[arg(
+        long,
+        env = "BQ_PLAYLIST_TABLE_ID",
+        help = "The optional playlist table"
+    )]
+    pub bq_playlist_table_id: Option<String>,
+    #[arg(
+        long,
+        env = "BQ_CONVERSION_ERROR_TABLE_ID",
+        help = "The optional conversion error table"
+    )]
+    pub bq_conversion_error_table_id: Option<String>,
+    #[arg(
+        long,
+        env = "BQ_WRITE_MODE",
+        default_value = "streaming",
+        help = "How the rows are written: streaming, load or merge"
+    )]
+    pub write_mode: bq_client::WriteMode,
+    #[arg(
+        long,
+        env = "BQ_MAX_BATCH_ROWS",
+        help = "The most rows in an insertAll request [default: 500]"
+    )]
+    pub bq_max_batch_rows: Option<usize>,
+    #[arg(
+        long,
+        env = "BQ_MAX_BATCH_BYTES",
+        help = "The largest insertAll request in bytes [default: 9 MiB]"
+    )]
+    pub bq_max_batch_bytes: Option<usize>,
+    #[arg(
+        long,
+        env = "BQ_MAX_CONCURRENT_INSERTS",
+        help = "The number of insertAll requests sent at once [default: 4]"
+    )]
+    pub bq_max_concurrent_inserts: Option<usize>,
+}
+
+#[derive(Args)]
+#[command(next_help_heading = "Local state")]
+pub struct CheckpointArgs {
+    #[arg(
+        long,
+        env = "CHECKPOINT_PATH",
+        default_value = "checkpoint.json",
+        help = "The file of the last synced commit, unless the sink keeps it"
+    )]
+    pub checkpoint_path: String,
+}
+
+#[derive(Args)]
+#[command(next_help_heading = "Local state")]
+pub struct ReportArgs {
+    #[arg(
+        long,
+        env = "CONVERSION_REPORT_PATH",
+        default_value = "conversion_errors.json",
+        help = "The file the commits that could not be converted are added to"
+    )]
+    pub conversion_report_path: String,
+}
+
+#[derive(Args)]
+#[command(next_help_heading = "Local state")]
+pub struct CacheArgs {
+    #[arg(
+        long,
+        env = "CACHE_DIR",
+        default_value = "cache",
+        help = "The directory of the raw commit cache"
+    )]
+    pub cache_dir: String,
+}
+
+#[derive(Args)]
+#[command(next_help_heading = "Local state")]
+pub struct RowsArgs {
+    #[arg(
+        long,
+        env = "ROWS_DIR",
+        default_value = "rows",
+        help = "The directory of the row files between convert and load"
+    )]
+    pub rows_dir: String,
+}
+
+// Parses the options. The blank environment variables, e.g. those left empty in .env,
+// count as unset, so that the defaults apply instead of the empty values.
+pub fn parse() -> Cli {
+    let matches = ignore_blank_env(Cli::command()).get_matches();
+    return Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+}
+
+fn ignore_blank_env(command: clap::Command) -> clap::Command {
+    return command
+        .mut_args(|arg| {
+            let is_blank = arg
+                .get_env()
+                .and_then(env::var_os)
+                .is_some_and(|value| value.is_empty());
+            if is_blank {
+                arg.env(None)
+            } else {
+                arg
+            }
+        })
+        .mut_subcommands(ignore_blank_env);
+}
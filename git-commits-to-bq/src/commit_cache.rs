@@ -0,0 +1,116 @@
+use chrono::prelude::*;
+use futures::stream::{self, StreamExt};
+use std::error::Error;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use crate::commit_source::CommitSource;
+use crate::github_client::defs::Commit;
+
+// Print the progress every this number of commits
+const PROGRESS_INTERVAL: usize = 100;
+
+// The raw commits dumped by the fetch command, one JSON file per commit in <dir>/commits/,
+// and their SHAs in ascending order in <dir>/commit_shas.json
+pub struct CommitCache {
+    dir: PathBuf,
+}
+
+impl CommitCache {
+    fn commit_path(&self, sha: &str) -> PathBuf {
+        return self.dir.join("commits").join(format!("{}.json", sha));
+    }
+
+    fn commit_shas_path(&self) -> PathBuf {
+        return self.dir.join("commit_shas.json");
+    }
+
+    // Fetches the commits that are not cached yet. A commit never changes once made,
+    // so a cached commit is never fetched again.
+    pub async fn fill(
+        &self,
+        commit_source: &impl CommitSource,
+        concurrency: usize,
+    ) -> Result<(), Box<dyn Error>> {
+        fs::create_dir_all(self.dir.join("commits"))?;
+        let commit_shas = commit_source.fetch_commit_shas(None).await?;
+        let missing_shas: Vec<&String> = commit_shas
+            .iter()
+            .filter(|sha| !self.commit_path(sha).exists())
+            .collect();
+        println!(
+            "Fetching {} of {} commits",
+            missing_shas.len(),
+            commit_shas.len()
+        );
+
+        let mut commits = stream::iter(missing_shas.iter())
+            .map(|sha| commit_source.fetch_commit_by_sha(sha))
+            .buffer_unordered(concurrency.max(1));
+        let mut fetched_commit_count = 0;
+        while let Some(result) = commits.next().await {
+            let commit = result?;
+            write_atomically(
+                &self.commit_path(&commit.sha),
+                &serde_json::to_string(&commit)?,
+            )?;
+            fetched_commit_count += 1;
+            if fetched_commit_count % PROGRESS_INTERVAL == 0
+                || fetched_commit_count == missing_shas.len()
+            {
+                println!(
+                    "Fetched {}/{} commits",
+                    fetched_commit_count,
+                    missing_shas.len()
+                );
+            }
+        }
+
+        // Written last, so that every listed commit is in the cache
+        write_atomically(
+            &self.commit_shas_path(),
+            &serde_json::to_string(&commit_shas)?,
+        )?;
+        return Ok(());
+    }
+}
+
+impl CommitSource for CommitCache {
+    async fn fetch_commit_shas(
+        &self,
+        _since: Option<DateTime<Utc>>,
+    ) -> Result<Vec<String>, Box<dyn Error>> {
+        let content = match fs::read_to_string(self.commit_shas_path()) {
+            Ok(content) => content,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                return Err(From::from(format!(
+                    "No commits are cached in {}; run the fetch command first",
+                    self.dir.display()
+                )))
+            }
+            Err(e) => return Err(Box::new(e)),
+        };
+        return Ok(serde_json::from_str(&content)?);
+    }
+
+    async fn fetch_commit_by_sha(&self, sha: &str) -> Result<Commit, Box<dyn Error>> {
+        let content = fs::read_to_string(self.commit_path(sha))?;
+        return Ok(serde_json::from_str(&content)?);
+    }
+}
+
+// Writes a temporary file and renames it into place, so that a fetch stopped halfway
+// never leaves a truncated file, which would count as cached and never be fetched again
+fn write_atomically(path: &Path, content: &str) -> Result<(), Box<dyn Error>> {
+    let temporary_path = path.with_extension("json.tmp");
+    fs::write(&temporary_path, content)?;
+    fs::rename(&temporary_path, path)?;
+    return Ok(());
+}
+
+pub fn new(dir: &str) -> CommitCache {
+    return CommitCache {
+        dir: PathBuf::from(dir),
+    };
+}
@@ -0,0 +1,81 @@
+use chrono::prelude::*;
+use std::error::Error;
+
+use crate::config::RepoSource;
+use crate::error::AppError;
+use crate::git_client::{self, GitClient};
+use crate::github_client::defs::Commit;
+use crate::github_client::{self, GithubClient};
+
+// A repository the Spotify log commits are read from,
+// either through the GitHub API or from a local clone
+pub trait CommitSource {
+    // Returns the commit SHAs in ascending order.
+    // A source that lists the commits by pages leaves out those made before `since`,
+    // while the others return them all.
+    async fn fetch_commit_shas(
+        &self,
+        since: Option<DateTime<Utc>>,
+    ) -> Result<Vec<String>, Box<dyn Error>>;
+
+    async fn fetch_commit_by_sha(&self, sha: &str) -> Result<Commit, Box<dyn Error>>;
+}
+
+// The source chosen at runtime
+pub enum AnyCommitSource {
+    Local(GitClient),
+    Github(Box<GithubClient>),
+}
+
+impl AnyCommitSource {
+    // Each source fails with its own exit code
+    pub fn error(&self, e: Box<dyn Error>) -> AppError {
+        return match self {
+            AnyCommitSource::Local(_) => AppError::Git(e),
+            AnyCommitSource::Github(_) => AppError::Github(e),
+        };
+    }
+}
+
+impl CommitSource for AnyCommitSource {
+    async fn fetch_commit_shas(
+        &self,
+        since: Option<DateTime<Utc>>,
+    ) -> Result<Vec<String>, Box<dyn Error>> {
+        return match self {
+            AnyCommitSource::Local(git_client) => git_client.fetch_commit_shas(since).await,
+            AnyCommitSource::Github(github_client) => github_client.fetch_commit_shas(since).await,
+        };
+    }
+
+    async fn fetch_commit_by_sha(&self, sha: &str) -> Result<Commit, Box<dyn Error>> {
+        return match self {
+            AnyCommitSource::Local(git_client) => git_client.fetch_commit_by_sha(sha).await,
+            AnyCommitSource::Github(github_client) => github_client.fetch_commit_by_sha(sha).await,
+        };
+    }
+}
+
+pub fn new(repo_source: &RepoSource) -> Result<AnyCommitSource, AppError> {
+    return match repo_source {
+        RepoSource::Local { path, branch } => {
+            let git_client = git_client::new(path, branch.clone()).map_err(AppError::Git)?;
+            Ok(AnyCommitSource::Local(git_client))
+        }
+        RepoSource::Github {
+            api_url,
+            token,
+            owner,
+            name,
+            commit_filter,
+            download_concurrency,
+        } => Ok(AnyCommitSource::Github(Box::new(github_client::new(
+            api_url,
+            token,
+            owner,
+            name,
+            commit_filter.clone(),
+            *download_concurrency,
+        )))),
+    };
+}
@@ -0,0 +1,241 @@
+use crate::bq_client;
+use crate::cli::{BigQueryArgs, LogArgs, SinkArgs, SinkKind, SourceArgs, SyncArgs};
+use crate::error::AppError;
+use crate::github_client;
+use crate::spotify_log::defs::LogFilter;
+
+// The optional tables are only written when their IDs are given
+#[derive(Clone, Default)]
+pub struct TableIds {
+    pub action: String,
+    pub track: String,
+    pub artist: String,
+    pub track_history: Option<String>,
+    pub playlist_action: Option<String>,
+    pub playlist: Option<String>,
+    pub conversion_error: Option<String>,
+}
+
+// Where the Spotify log commits are read from
+pub enum RepoSource {
+    Local {
+        path: String,
+        branch: Option<String>, // HEAD is read when it is not given
+    },
+    Github {
+        api_url: String,
+        token: String,
+        owner: String,
+        name: String,
+        commit_filter: github_client::CommitFilter,
+        download_concurrency: usize, // The raw snapshot files downloaded at once
+    },
+}
+
+#[derive(Clone)]
+pub struct BigQueryConfig {
+    pub credentials: bq_client::auth::Credentials,
+    pub project_id: String,
+    pub dataset_id: String,
+    pub dataset_location: Option<String>,
+    pub table_ids: TableIds,
+    pub write_mode: bq_client::WriteMode,
+    pub insert_limits: bq_client::InsertLimits,
+}
+
+// Where the converted rows are written
+pub enum SinkConfig {
+    BigQuery(Box<BigQueryConfig>),
+    Ndjson {
+        dir: String,
+    },
+    Csv {
+        dir: String,
+    },
+    Parquet {
+        dir: String,
+        partition_actions_by_month: bool,
+    },
+    Sqlite {
+        path: String,
+    },
+}
+
+// A repository to sync, and where its rows and its checkpoint are kept
+pub struct RepoConfig {
+    pub name: Option<String>, // Only the repositories listed in a config file have a name
+    pub source: RepoSource,
+    pub log_filter: LogFilter,
+    pub sink: SinkConfig,
+    pub checkpoint_path: String,
+    pub conversion_report_path: String,
+}
+
+// Checks the settings that are only required by some sources and sinks,
+// and collects every problem instead of stopping at the first one
+#[derive(Default)]
+pub struct Validator {
+    problems: Vec<String>,
+}
+
+impl Validator {
+    fn required(&mut self, env_name: &str, value: &Option<String>) -> String {
+        return match non_empty(value) {
+            Some(value) => value,
+            None => {
+                self.problems.push(format!(
+                    "--{} ({}) is not set",
+                    env_name.to_lowercase().replace('_', "-"),
+                    env_name
+                ));
+                String::new()
+            }
+        };
+    }
+
+    pub fn problem(&mut self, problem: String) {
+        self.problems.push(problem);
+    }
+
+    pub fn finish(self) -> Result<(), AppError> {
+        if !self.problems.is_empty() {
+            return Err(AppError::Config(self.problems));
+        }
+        return Ok(());
+    }
+}
+
+// The single repository given by the options
+pub fn repo(validator: &mut Validator, args: &SyncArgs) -> RepoConfig {
+    return RepoConfig {
+        name: None,
+        source: repo_source(validator, &args.source),
+        log_filter: log_filter(&args.source.log),
+        sink: sink(validator, &args.sink),
+        checkpoint_path: args.checkpoint.checkpoint_path.to_string(),
+        conversion_report_path: args.report.conversion_report_path.to_string(),
+    };
+}
+
+pub fn log_filter(args: &LogArgs) -> LogFilter {
+    return LogFilter {
+        committer_name: args.log_committer_name.to_string(),
+        started_at: args.log_started_at,
+    };
+}
+
+// Read the log from a local clone if its path is given, otherwise through the GitHub API
+pub fn repo_source(validator: &mut Validator, args: &SourceArgs) -> RepoSource {
+    if let Some(path) = non_empty(&args.local_repo_path) {
+        return RepoSource::Local {
+            path,
+            branch: non_empty(&args.github_branch),
+        };
+    }
+    return RepoSource::Github {
+        api_url: args.github_api_url.to_string(),
+        token: validator.required("GITHUB_TOKEN", &args.github_token),
+        owner: validator.required("REPO_OWNER", &args.repo_owner),
+        name: validator.required("REPO_NAME", &args.repo_name),
+        commit_filter: github_client::CommitFilter {
+            since: args.github_commits_since,
+            until: args.github_commits_until,
+            branch: non_empty(&args.github_branch),
+        },
+        download_concurrency: args.download_concurrency,
+    };
+}
+
+pub fn sink(validator: &mut Validator, args: &SinkArgs) -> SinkConfig {
+    return match args.sink {
+        SinkKind::Bigquery => {
+            let table_ids = table_ids(validator, &args.bigquery);
+            SinkConfig::BigQuery(Box::new(bigquery(validator, &args.bigquery, table_ids)))
+        }
+        SinkKind::Ndjson => SinkConfig::Ndjson {
+            dir: args.output_dir.to_string(),
+        },
+        SinkKind::Csv => SinkConfig::Csv {
+            dir: args.output_dir.to_string(),
+        },
+        SinkKind::Parquet => SinkConfig::Parquet {
+            dir: args.output_dir.to_string(),
+            partition_actions_by_month: args.parquet_partition_by_month,
+        },
+        SinkKind::Sqlite => SinkConfig::Sqlite {
+            path: args.sqlite_path.to_string(),
+        },
+    };
+}
+
+// The BigQuery settings are only required when the rows are written into BigQuery
+pub fn bigquery(
+    validator: &mut Validator,
+    args: &BigQueryArgs,
+    table_ids: TableIds,
+) -> BigQueryConfig {
+    // Prefer a service account key, whose tokens are refreshed automatically
+    let credentials = match non_empty(&args.gcp_service_account_key_path) {
+        Some(key_path) => match bq_client::auth::read_service_account_key(&key_path) {
+            Ok(mut key) => {
+                if let Some(token_uri) = non_empty(&args.gcp_token_uri) {
+                    key.token_uri = token_uri;
+                }
+                bq_client::auth::Credentials::ServiceAccount(key)
+            }
+            Err(e) => {
+                validator.problems.push(format!(
+                    "GCP_SERVICE_ACCOUNT_KEY_PATH cannot be read: {}",
+                    e
+                ));
+                bq_client::auth::Credentials::AccessToken(String::new())
+            }
+        },
+        None => match non_empty(&args.gcp_access_token) {
+            Some(token) => bq_client::auth::Credentials::AccessToken(token),
+            None => {
+                validator.problems.push(
+                    "--gcp-service-account-key-path (GCP_SERVICE_ACCOUNT_KEY_PATH) or --gcp-access-token (GCP_ACCESS_TOKEN) is not set".to_string(),
+                );
+                bq_client::auth::Credentials::AccessToken(String::new())
+            }
+        },
+    };
+    let default_insert_limits = bq_client::InsertLimits::default();
+    return BigQueryConfig {
+        credentials,
+        project_id: validator.required("BQ_PROJECT_ID", &args.bq_project_id),
+        dataset_id: validator.required("BQ_DATASET_ID", &args.bq_dataset_id),
+        dataset_location: non_empty(&args.bq_dataset_location),
+        table_ids,
+        write_mode: args.write_mode,
+        insert_limits: bq_client::InsertLimits {
+            max_batch_rows: args
+                .bq_max_batch_rows
+                .unwrap_or(default_insert_limits.max_batch_rows),
+            max_batch_bytes: args
+                .bq_max_batch_bytes
+                .unwrap_or(default_insert_limits.max_batch_bytes),
+            max_concurrent_requests: args
+                .bq_max_concurrent_inserts
+                .unwrap_or(default_insert_limits.max_concurrent_requests),
+        },
+    };
+}
+
+pub fn table_ids(validator: &mut Validator, args: &BigQueryArgs) -> TableIds {
+    return TableIds {
+        action: validator.required("BQ_ACTION_TABLE_ID", &args.bq_action_table_id),
+        track: validator.required("BQ_TRACK_TABLE_ID", &args.bq_track_table_id),
+        artist: validator.required("BQ_ARTIST_TABLE_ID", &args.bq_artist_table_id),
+        track_history: non_empty(&args.bq_track_history_table_id),
+        playlist_action: non_empty(&args.bq_playlist_action_table_id),
+        playlist: non_empty(&args.bq_playlist_table_id),
+        conversion_error: non_empty(&args.bq_conversion_error_table_id),
+    };
+}
+
+// An empty value, e.g. `GITHUB_BRANCH=` in .env, is the same as no value
+pub fn non_empty(value: &Option<String>) -> Option<String> {
+    return value.clone().filter(|value| !value.is_empty());
+}
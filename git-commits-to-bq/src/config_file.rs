@@ -0,0 +1,591 @@
+use chrono::prelude::*;
+use lazy_static::lazy_static;
+use regex::{Captures, Regex};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+use crate::cli::{SinkKind, SyncArgs};
+use crate::config::{
+    self, BigQueryConfig, RepoConfig, RepoSource, SinkConfig, TableIds, Validator,
+};
+use crate::github_client;
+use crate::spotify_log::defs::LogFilter;
+
+lazy_static! {
+    // ${NAME} in a string is replaced with the environment variable, e.g. to keep the tokens out of the file
+    static ref ENV_VAR_RE: Regex = Regex::new(r"\$\{([A-Za-z_][A-Za-z0-9_]*)\}").unwrap();
+    // The name of a repository is a part of its default paths
+    static ref REPO_NAME_RE: Regex = Regex::new(r"^[A-Za-z0-9_-]+$").unwrap();
+}
+
+// The repositories synced in one run.
+// What a repository does not set is taken from the options, as when the file is not used.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    #[serde(default)]
+    repos: Vec<RepoEntry>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RepoEntry {
+    name: String,
+    owner: Option<String>,
+    repo: Option<String>,
+    local_path: Option<String>, // Read from a local clone instead of the GitHub API
+    branch: Option<String>,
+    github_token: Option<String>,
+    committer_name: Option<String>,
+    started_at: Option<toml::value::Datetime>,
+    dataset_id: Option<String>,
+    #[serde(default)]
+    tables: TableEntry,
+    output_dir: Option<String>, // Of the ndjson, csv and parquet sinks
+    sqlite_path: Option<String>,
+    checkpoint_path: Option<String>,
+    conversion_report_path: Option<String>,
+}
+
+// The BigQuery table IDs
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TableEntry {
+    action: Option<String>,
+    track: Option<String>,
+    artist: Option<String>,
+    track_history: Option<String>,
+    playlist_action: Option<String>,
+    playlist: Option<String>,
+    conversion_error: Option<String>,
+}
+
+// Reads the repositories in the file at `path`, and records every problem with them in the validator
+pub fn repos(validator: &mut Validator, path: &str, args: &SyncArgs) -> Vec<RepoConfig> {
+    let config_file = match read(path) {
+        Ok(config_file) => config_file,
+        Err(e) => {
+            validator.problem(format!("{} cannot be read: {}", path, e));
+            return vec![];
+        }
+    };
+    if config_file.repos.is_empty() {
+        validator.problem(format!("{} has no [[repos]]", path));
+    }
+
+    // Every repository is written with the same credentials, so they are only read once
+    let base_sink = match args.sink.sink {
+        SinkKind::Bigquery => SinkConfig::BigQuery(Box::new(config::bigquery(
+            validator,
+            &args.sink.bigquery,
+            TableIds::default(),
+        ))),
+        _ => config::sink(validator, &args.sink),
+    };
+    let mut names = HashSet::new();
+    let mut checkpoint_paths = HashSet::new();
+    let mut target_to_repo_name: HashMap<String, String> = HashMap::new();
+    let mut repos = vec![];
+    for entry in &config_file.repos {
+        if !REPO_NAME_RE.is_match(&entry.name) {
+            validator.problem(format!(
+                "The repository name {:?} may only contain letters, digits, _ and -",
+                entry.name
+            ));
+        }
+        if !names.insert(entry.name.to_string()) {
+            validator.problem(format!("The repository name {} is used twice", entry.name));
+        }
+        let repo = repo(validator, entry, args, &base_sink);
+        // A shared checkpoint would make one repository skip the commits of the other
+        if !checkpoint_paths.insert(repo.checkpoint_path.to_string()) {
+            validator.problem(format!(
+                "repos.{}.checkpoint_path {} is used by another repository",
+                entry.name, repo.checkpoint_path
+            ));
+        }
+        // The rows of two repositories in one table could not be told apart,
+        // and a shared SQLite database would also share its checkpoint
+        for (field, target) in sink_targets(&repo.sink) {
+            match target_to_repo_name.get(&target) {
+                Some(repo_name) if *repo_name != entry.name => {
+                    validator.problem(format!(
+                        "repos.{}.{} {} is also written by repos.{}",
+                        entry.name, field, target, repo_name
+                    ));
+                }
+                _ => {
+                    target_to_repo_name.insert(target, entry.name.to_string());
+                }
+            }
+        }
+        repos.push(repo);
+    }
+    return repos;
+}
+
+fn read(path: &str) -> Result<ConfigFile, Box<dyn Error>> {
+    let content = fs::read_to_string(path)?;
+    return Ok(toml::from_str(&content)?);
+}
+
+fn repo(
+    validator: &mut Validator,
+    entry: &RepoEntry,
+    args: &SyncArgs,
+    base_sink: &SinkConfig,
+) -> RepoConfig {
+    let name = &entry.name;
+    let field = |key: &str| format!("repos.{}.{}", name, key);
+
+    let branch = interpolate(validator, &field("branch"), &entry.branch)
+        .or_else(|| config::non_empty(&args.source.github_branch));
+    let source = match interpolate(validator, &field("local_path"), &entry.local_path) {
+        Some(path) => RepoSource::Local { path, branch },
+        None => {
+            let token = interpolate(validator, &field("github_token"), &entry.github_token)
+                .or_else(|| config::non_empty(&args.source.github_token));
+            let owner = interpolate(validator, &field("owner"), &entry.owner);
+            let repo_name = interpolate(validator, &field("repo"), &entry.repo);
+            RepoSource::Github {
+                api_url: args.source.github_api_url.to_string(),
+                token: required(
+                    validator,
+                    &format!("{} or --github-token (GITHUB_TOKEN)", field("github_token")),
+                    token,
+                ),
+                owner: required(validator, &field("owner"), owner),
+                name: required(validator, &field("repo"), repo_name),
+                commit_filter: github_client::CommitFilter {
+                    since: args.source.github_commits_since,
+                    until: args.source.github_commits_until,
+                    branch,
+                },
+                download_concurrency: args.source.download_concurrency,
+            }
+        }
+    };
+
+    let default_log_filter = config::log_filter(&args.source.log);
+    let log_filter = LogFilter {
+        committer_name: interpolate(validator, &field("committer_name"), &entry.committer_name)
+            .unwrap_or(default_log_filter.committer_name),
+        started_at: match &entry.started_at {
+            Some(started_at) => datetime(validator, &field("started_at"), started_at)
+                .unwrap_or(default_log_filter.started_at),
+            None => default_log_filter.started_at,
+        },
+    };
+
+    let sink = repo_sink(validator, entry, args, base_sink);
+    return RepoConfig {
+        name: Some(name.to_string()),
+        source,
+        log_filter,
+        sink,
+        checkpoint_path: interpolate(validator, &field("checkpoint_path"), &entry.checkpoint_path)
+            .unwrap_or_else(|| format!("checkpoint-{}.json", name)),
+        conversion_report_path: interpolate(
+            validator,
+            &field("conversion_report_path"),
+            &entry.conversion_report_path,
+        )
+        .unwrap_or_else(|| format!("conversion_errors-{}.json", name)),
+    };
+}
+
+// The sink of the options, with the tables of the repository.
+// The local sinks write each repository into its own files.
+fn repo_sink(
+    validator: &mut Validator,
+    entry: &RepoEntry,
+    args: &SyncArgs,
+    base_sink: &SinkConfig,
+) -> SinkConfig {
+    let name = &entry.name;
+    let field = |key: &str| format!("repos.{}.{}", name, key);
+    let output_dir = interpolate(validator, &field("output_dir"), &entry.output_dir)
+        .unwrap_or_else(|| {
+            Path::new(&args.sink.output_dir)
+                .join(name)
+                .to_string_lossy()
+                .to_string()
+        });
+    return match base_sink {
+        SinkConfig::BigQuery(bigquery) => SinkConfig::BigQuery(Box::new(BigQueryConfig {
+            dataset_id: interpolate(validator, &field("dataset_id"), &entry.dataset_id)
+                .unwrap_or_else(|| bigquery.dataset_id.to_string()),
+            table_ids: table_ids(validator, name, &entry.tables, args),
+            ..*bigquery.clone()
+        })),
+        SinkConfig::Ndjson { .. } => SinkConfig::Ndjson { dir: output_dir },
+        SinkConfig::Csv { .. } => SinkConfig::Csv { dir: output_dir },
+        SinkConfig::Parquet {
+            partition_actions_by_month,
+            ..
+        } => SinkConfig::Parquet {
+            dir: output_dir,
+            partition_actions_by_month: *partition_actions_by_month,
+        },
+        SinkConfig::Sqlite { .. } => SinkConfig::Sqlite {
+            path: interpolate(validator, &field("sqlite_path"), &entry.sqlite_path)
+                .unwrap_or_else(|| format!("{}.sqlite", name)),
+        },
+    };
+}
+
+// What the sink writes the rows into, with the field of the config file that sets it
+fn sink_targets(sink: &SinkConfig) -> Vec<(String, String)> {
+    return match sink {
+        SinkConfig::BigQuery(bigquery) => {
+            let table_ids = &bigquery.table_ids;
+            let tables = [
+                ("action", Some(&table_ids.action)),
+                ("track", Some(&table_ids.track)),
+                ("artist", Some(&table_ids.artist)),
+                ("track_history", table_ids.track_history.as_ref()),
+                ("playlist_action", table_ids.playlist_action.as_ref()),
+                ("playlist", table_ids.playlist.as_ref()),
+                ("conversion_error", table_ids.conversion_error.as_ref()),
+            ];
+            tables
+                .iter()
+                .filter_map(|(key, table_id)| {
+                    table_id.map(|table_id| {
+                        (
+                            format!("tables.{}", key),
+                            format!("{}.{}", bigquery.dataset_id, table_id),
+                        )
+                    })
+                })
+                .collect()
+        }
+        SinkConfig::Ndjson { dir } | SinkConfig::Csv { dir } | SinkConfig::Parquet { dir, .. } => {
+            vec![("output_dir".to_string(), dir.to_string())]
+        }
+        SinkConfig::Sqlite { path } => vec![("sqlite_path".to_string(), path.to_string())],
+    };
+}
+
+// The tables the repository does not set are the ones given by the options
+fn table_ids(
+    validator: &mut Validator,
+    name: &str,
+    tables: &TableEntry,
+    args: &SyncArgs,
+) -> TableIds {
+    let bigquery_args = &args.sink.bigquery;
+    let mut table_id = |key: &str, value: &Option<String>, default: &Option<String>| {
+        interpolate(validator, &format!("repos.{}.tables.{}", name, key), value)
+            .or_else(|| config::non_empty(default))
+    };
+    let action = table_id("action", &tables.action, &bigquery_args.bq_action_table_id);
+    let track = table_id("track", &tables.track, &bigquery_args.bq_track_table_id);
+    let artist = table_id("artist", &tables.artist, &bigquery_args.bq_artist_table_id);
+    let track_history = table_id(
+        "track_history",
+        &tables.track_history,
+        &bigquery_args.bq_track_history_table_id,
+    );
+    let playlist_action = table_id(
+        "playlist_action",
+        &tables.playlist_action,
+        &bigquery_args.bq_playlist_action_table_id,
+    );
+    let playlist = table_id(
+        "playlist",
+        &tables.playlist,
+        &bigquery_args.bq_playlist_table_id,
+    );
+    let conversion_error = table_id(
+        "conversion_error",
+        &tables.conversion_error,
+        &bigquery_args.bq_conversion_error_table_id,
+    );
+    return TableIds {
+        action: required(
+            validator,
+            &format!(
+                "repos.{}.tables.action or --bq-action-table-id (BQ_ACTION_TABLE_ID)",
+                name
+            ),
+            action,
+        ),
+        track: required(
+            validator,
+            &format!(
+                "repos.{}.tables.track or --bq-track-table-id (BQ_TRACK_TABLE_ID)",
+                name
+            ),
+            track,
+        ),
+        artist: required(
+            validator,
+            &format!(
+                "repos.{}.tables.artist or --bq-artist-table-id (BQ_ARTIST_TABLE_ID)",
+                name
+            ),
+            artist,
+        ),
+        track_history,
+        playlist_action,
+        playlist,
+        conversion_error,
+    };
+}
+
+// Replaces the ${NAME} in the value with the environment variables.
+// An empty value is the same as no value, as with the options.
+fn interpolate(validator: &mut Validator, field: &str, value: &Option<String>) -> Option<String> {
+    let value = config::non_empty(value)?;
+    let mut missing_names = vec![];
+    let interpolated =
+        ENV_VAR_RE.replace_all(&value, |captures: &Captures| match env::var(&captures[1]) {
+            Ok(env_value) => env_value,
+            // Kept as it is, since the run stops at the problem anyway
+            Err(_) => {
+                missing_names.push(captures[1].to_string());
+                captures[0].to_string()
+            }
+        });
+    for missing_name in missing_names {
+        validator.problem(format!(
+            "{} refers to the environment variable {}, which is not set",
+            field, missing_name
+        ));
+    }
+    return config::non_empty(&Some(interpolated.to_string()));
+}
+
+fn required(validator: &mut Validator, field: &str, value: Option<String>) -> String {
+    return match value {
+        Some(value) => value,
+        None => {
+            validator.problem(format!("{} is not set", field));
+            String::new()
+        }
+    };
+}
+
+// A date without a time is the start of the day in UTC
+fn datetime(
+    validator: &mut Validator,
+    field: &str,
+    value: &toml::value::Datetime,
+) -> Option<DateTime<Utc>> {
+    let text = value.to_string();
+    if let Ok(datetime) = text.parse::<DateTime<Utc>>() {
+        return Some(datetime);
+    }
+    if let Some(datetime) = text
+        .parse::<NaiveDate>()
+        .ok()
+        .and_then(|date| date.and_hms_opt(0, 0, 0))
+    {
+        return Some(Utc.from_utc_datetime(&datetime));
+    }
+    validator.problem(format!(
+        "{} {} has no time zone; write it like 2019-10-03T00:00:00Z",
+        field, text
+    ));
+    return None;
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use std::process;
+
+    use super::*;
+    use crate::cli::Cli;
+    use crate::error::AppError;
+
+    fn problems(validator: Validator) -> Vec<String> {
+        return match validator.finish() {
+            Ok(()) => vec![],
+            Err(AppError::Config(problems)) => problems,
+            Err(e) => panic!("Unexpected error: {}", e),
+        };
+    }
+
+    fn toml_datetime(text: &str) -> toml::value::Datetime {
+        return text.parse().unwrap();
+    }
+
+    // Reads the config file with the content, with the options given on the command line
+    fn repos_of(name: &str, content: &str, options: &[&str]) -> (Vec<RepoConfig>, Vec<String>) {
+        let path =
+            std::env::temp_dir().join(format!("git-commits-to-bq-{}-{}.toml", name, process::id()));
+        fs::write(&path, content).unwrap();
+        let args = Cli::try_parse_from(["git-commits-to-bq"].iter().chain(options))
+            .unwrap()
+            .sync;
+        let mut validator = Validator::default();
+        let repos = repos(&mut validator, path.to_str().unwrap(), &args);
+        fs::remove_file(&path).unwrap();
+        return (repos, problems(validator));
+    }
+
+    #[test]
+    fn repos_rejects_a_shared_sqlite_database() {
+        let (_, problems) = repos_of(
+            "sqlite",
+            "[[repos]]\nname = \"a\"\nlocal_path = \"a\"\nsqlite_path = \"log.sqlite\"\n\
+             [[repos]]\nname = \"b\"\nlocal_path = \"b\"\nsqlite_path = \"log.sqlite\"\n",
+            &["--sink", "sqlite"],
+        );
+        assert_eq!(
+            problems,
+            vec!["repos.b.sqlite_path log.sqlite is also written by repos.a"]
+        );
+    }
+
+    #[test]
+    fn repos_rejects_shared_bigquery_tables() {
+        let (_, problems) = repos_of(
+            "bigquery",
+            "[[repos]]\nname = \"a\"\nlocal_path = \"a\"\n\
+             [[repos]]\nname = \"b\"\nlocal_path = \"b\"\ndataset_id = \"other\"\n\
+             [[repos]]\nname = \"c\"\nlocal_path = \"c\"\n[repos.tables]\naction = \"c_actions\"\n",
+            &[
+                "--sink",
+                "bigquery",
+                "--gcp-access-token",
+                "token",
+                "--bq-project-id",
+                "project",
+                "--bq-dataset-id",
+                "log",
+                "--bq-action-table-id",
+                "actions",
+                "--bq-track-table-id",
+                "tracks",
+                "--bq-artist-table-id",
+                "artists",
+            ],
+        );
+        assert_eq!(
+            problems,
+            vec![
+                "repos.c.tables.track log.tracks is also written by repos.a",
+                "repos.c.tables.artist log.artists is also written by repos.a",
+            ]
+        );
+    }
+
+    #[test]
+    fn repos_passes_the_branch_to_a_local_clone() {
+        let (repos, problems) = repos_of(
+            "branch",
+            "[[repos]]\nname = \"a\"\nlocal_path = \"a\"\nbranch = \"backup\"\n",
+            &["--sink", "sqlite"],
+        );
+        assert!(problems.is_empty());
+        assert!(matches!(
+            &repos[0].source,
+            RepoSource::Local { path, branch } if path == "a" && branch.as_deref() == Some("backup")
+        ));
+    }
+
+    #[test]
+    fn interpolate_replaces_the_environment_variables() {
+        let mut validator = Validator::default();
+
+        // CARGO_MANIFEST_DIR is set by cargo for the tests
+        let value = interpolate(
+            &mut validator,
+            "repos.log.local_path",
+            &Some("${CARGO_MANIFEST_DIR}/spotify-backup".to_string()),
+        );
+
+        assert_eq!(
+            value,
+            Some(format!("{}/spotify-backup", env!("CARGO_MANIFEST_DIR")))
+        );
+        assert!(problems(validator).is_empty());
+    }
+
+    #[test]
+    fn interpolate_reports_the_missing_environment_variables() {
+        let mut validator = Validator::default();
+
+        let value = interpolate(
+            &mut validator,
+            "repos.log.github_token",
+            &Some("${CONFIG_FILE_TEST_MISSING}".to_string()),
+        );
+
+        assert_eq!(value, Some("${CONFIG_FILE_TEST_MISSING}".to_string()));
+        assert_eq!(
+            problems(validator),
+            vec!["repos.log.github_token refers to the environment variable CONFIG_FILE_TEST_MISSING, which is not set"]
+        );
+    }
+
+    #[test]
+    fn interpolate_of_an_empty_value_is_none() {
+        let mut validator = Validator::default();
+
+        assert_eq!(interpolate(&mut validator, "field", &None), None);
+        assert_eq!(
+            interpolate(&mut validator, "field", &Some(String::new())),
+            None
+        );
+        assert!(problems(validator).is_empty());
+    }
+
+    #[test]
+    fn datetime_reads_an_offset_datetime() {
+        let mut validator = Validator::default();
+
+        let value = datetime(
+            &mut validator,
+            "log_started_at",
+            &toml_datetime("2019-10-03T09:00:00+09:00"),
+        );
+
+        assert_eq!(
+            value,
+            Some(Utc.with_ymd_and_hms(2019, 10, 3, 0, 0, 0).unwrap())
+        );
+        assert!(problems(validator).is_empty());
+    }
+
+    #[test]
+    fn datetime_of_a_date_is_the_start_of_the_day_in_utc() {
+        let mut validator = Validator::default();
+
+        let value = datetime(
+            &mut validator,
+            "log_started_at",
+            &toml_datetime("2019-10-03"),
+        );
+
+        assert_eq!(
+            value,
+            Some(Utc.with_ymd_and_hms(2019, 10, 3, 0, 0, 0).unwrap())
+        );
+        assert!(problems(validator).is_empty());
+    }
+
+    #[test]
+    fn datetime_rejects_a_local_datetime() {
+        let mut validator = Validator::default();
+
+        let value = datetime(
+            &mut validator,
+            "log_started_at",
+            &toml_datetime("2019-10-03T00:00:00"),
+        );
+
+        assert_eq!(value, None);
+        assert_eq!(
+            problems(validator),
+            vec!["log_started_at 2019-10-03T00:00:00 has no time zone; write it like 2019-10-03T00:00:00Z"]
+        );
+    }
+}
@@ -0,0 +1,27 @@
+use std::error::Error;
+use std::fs;
+use std::io::ErrorKind;
+
+use crate::converter::ConversionErrorTableRow;
+
+// Adds the rows to the report file, keeping the rows of earlier runs.
+// A commit already in the report is not added again.
+// Returns the number of the added rows.
+pub fn append(path: &str, rows: &[ConversionErrorTableRow]) -> Result<usize, Box<dyn Error>> {
+    let mut report: Vec<ConversionErrorTableRow> = match fs::read_to_string(path) {
+        Ok(content) => serde_json::from_str(&content)?,
+        Err(e) if e.kind() == ErrorKind::NotFound => vec![],
+        Err(e) => return Err(Box::new(e)),
+    };
+    let report_length = report.len();
+    for row in rows {
+        let exists = report[..report_length]
+            .iter()
+            .any(|reported_row| reported_row.commit_sha == row.commit_sha);
+        if !exists {
+            report.push(row.clone());
+        }
+    }
+    fs::write(path, serde_json::to_string_pretty(&report)?)?;
+    return Ok(report.len() - report_length);
+}
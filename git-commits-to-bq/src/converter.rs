@@ -1,30 +1,337 @@
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::collections::HashMap;
 
-use crate::spotify_log::defs::{Artist, Track, TrackRelatedAction, TrackRelatedActionType};
+use crate::spotify_log::defs::{
+    Actions, Artist, ConversionError, ConversionErrorKind, PlaylistAction, PlaylistActionType,
+    Track, TrackRelatedAction, TrackRelatedActionType,
+};
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct ActionTableRow {
+    pub commit_sha: String,
     pub timestamp: String,
     pub action_type: String,
     pub source_playlist_id: Option<String>,
     pub destination_playlist_id: Option<String>,
     pub track_id: String,
+    pub source_position: Option<usize>,
+    pub destination_position: Option<usize>,
+    pub added_at: Option<String>,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct TrackTableRow {
     pub id: String,
     pub name: String,
     pub artist_ids: Vec<String>,
+    pub album_id: Option<String>,
+    pub album_name: Option<String>,
+    pub duration_ms: Option<u32>,
+    pub popularity: Option<u8>,
 }
 
-#[derive(Debug, Serialize)]
+// A version of a track, valid until the next version replaces it
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TrackHistoryTableRow {
+    pub id: String,
+    pub name: String,
+    pub artist_ids: Vec<String>,
+    pub valid_from: String,
+    pub valid_to: Option<String>, // None for the current version
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 pub struct ArtistTableRow {
     pub id: String,
     pub name: String,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PlaylistActionTableRow {
+    pub commit_sha: String,
+    pub timestamp: String,
+    pub action_type: String, // "creation" | "deletion" | "rename" | "modification"
+    pub playlist_id: String,
+    pub name: String,
+    pub previous_name: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PlaylistTableRow {
+    pub id: String,
+    pub name: String, // The latest name
+    pub first_seen_at: String,
+    pub last_seen_at: String,
+}
+
+// Also the format of the local conversion error report
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ConversionErrorTableRow {
+    pub commit_sha: String,
+    pub timestamp: String,
+    pub message: String,
+    pub kind: String, // "missing_snapshot" | "invalid_snapshot" | "no_track_change"
+    pub filename: Option<String>, // Only for an invalid snapshot
+    pub reason: String,
+}
+
+// The rows of every table converted from the actions of a run
+pub struct TableRows {
+    pub actions: Vec<ActionTableRow>,
+    pub tracks: Vec<TrackTableRow>,
+    pub artists: Vec<ArtistTableRow>,
+    pub track_history: Vec<TrackHistoryTableRow>,
+    pub playlist_actions: Vec<PlaylistActionTableRow>,
+    pub playlists: Vec<PlaylistTableRow>,
+    pub conversion_errors: Vec<ConversionErrorTableRow>,
+}
+
+// A stable natural key of a row, also used as the insertId for BigQuery
+pub trait RowKey {
+    fn row_key(&self) -> String;
+}
+
+// The positions tell apart the copies of a track changed in the same commit
+impl RowKey for ActionTableRow {
+    fn row_key(&self) -> String {
+        let source_position = self.source_position.map(|position| position.to_string());
+        let destination_position = self
+            .destination_position
+            .map(|position| position.to_string());
+        return format!(
+            "{}:{}:{}:{}:{}:{}:{}",
+            self.commit_sha,
+            self.track_id,
+            self.action_type,
+            self.source_playlist_id.as_deref().unwrap_or(""),
+            source_position.as_deref().unwrap_or(""),
+            self.destination_playlist_id.as_deref().unwrap_or(""),
+            destination_position.as_deref().unwrap_or("")
+        );
+    }
+}
+
+impl RowKey for TrackTableRow {
+    fn row_key(&self) -> String {
+        let duration_ms = self.duration_ms.map(|duration_ms| duration_ms.to_string());
+        let popularity = self.popularity.map(|popularity| popularity.to_string());
+        let mut fields = vec![self.name.as_str()];
+        fields.extend(self.artist_ids.iter().map(|artist_id| artist_id.as_str()));
+        for field in [&self.album_id, &self.album_name, &duration_ms, &popularity] {
+            fields.push(field.as_deref().unwrap_or(""));
+        }
+        return format!("{}:{}", self.id, content_hash(&fields));
+    }
+}
+
+impl RowKey for TrackHistoryTableRow {
+    fn row_key(&self) -> String {
+        return format!("{}:{}", self.id, self.valid_from);
+    }
+}
+
+impl RowKey for PlaylistActionTableRow {
+    fn row_key(&self) -> String {
+        return format!("{}:{}", self.commit_sha, self.playlist_id);
+    }
+}
+
+impl RowKey for PlaylistTableRow {
+    fn row_key(&self) -> String {
+        let fields = [
+            self.name.as_str(),
+            self.first_seen_at.as_str(),
+            self.last_seen_at.as_str(),
+        ];
+        return format!("{}:{}", self.id, content_hash(&fields));
+    }
+}
+
+impl RowKey for ConversionErrorTableRow {
+    fn row_key(&self) -> String {
+        return format!("{}:{}", self.commit_sha, self.kind);
+    }
+}
+
+impl RowKey for ArtistTableRow {
+    fn row_key(&self) -> String {
+        return format!("{}:{}", self.id, content_hash(&[self.name.as_str()]));
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColumnType {
+    String,
+    Integer,
+    Timestamp,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColumnMode {
+    Required,
+    Nullable,
+    Repeated,
+}
+
+#[derive(Debug)]
+pub struct Column {
+    pub name: &'static str,
+    pub column_type: ColumnType,
+    pub mode: ColumnMode,
+}
+
+// How a column of an existing row is updated when a row with the same key is upserted
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MergeRule {
+    Replace,
+    KeepMin,
+    KeepMax,
+}
+
+// The columns of the table a row type is stored in, in the order of the struct fields
+pub trait TableSchema {
+    fn columns() -> Vec<Column>;
+
+    fn merge_rule(_column_name: &str) -> MergeRule {
+        return MergeRule::Replace;
+    }
+}
+
+impl TableSchema for ActionTableRow {
+    fn columns() -> Vec<Column> {
+        return vec![
+            column("commit_sha", ColumnType::String, ColumnMode::Nullable),
+            column("timestamp", ColumnType::Timestamp, ColumnMode::Required),
+            column("action_type", ColumnType::String, ColumnMode::Required),
+            column(
+                "source_playlist_id",
+                ColumnType::String,
+                ColumnMode::Nullable,
+            ),
+            column(
+                "destination_playlist_id",
+                ColumnType::String,
+                ColumnMode::Nullable,
+            ),
+            column("track_id", ColumnType::String, ColumnMode::Required),
+            column("source_position", ColumnType::Integer, ColumnMode::Nullable),
+            column(
+                "destination_position",
+                ColumnType::Integer,
+                ColumnMode::Nullable,
+            ),
+            column("added_at", ColumnType::Timestamp, ColumnMode::Nullable),
+        ];
+    }
+}
+
+impl TableSchema for TrackTableRow {
+    fn columns() -> Vec<Column> {
+        return vec![
+            column("id", ColumnType::String, ColumnMode::Required),
+            column("name", ColumnType::String, ColumnMode::Required),
+            column("artist_ids", ColumnType::String, ColumnMode::Repeated),
+            column("album_id", ColumnType::String, ColumnMode::Nullable),
+            column("album_name", ColumnType::String, ColumnMode::Nullable),
+            column("duration_ms", ColumnType::Integer, ColumnMode::Nullable),
+            column("popularity", ColumnType::Integer, ColumnMode::Nullable),
+        ];
+    }
+}
+
+impl TableSchema for TrackHistoryTableRow {
+    fn columns() -> Vec<Column> {
+        return vec![
+            column("id", ColumnType::String, ColumnMode::Required),
+            column("name", ColumnType::String, ColumnMode::Required),
+            column("artist_ids", ColumnType::String, ColumnMode::Repeated),
+            column("valid_from", ColumnType::Timestamp, ColumnMode::Required),
+            column("valid_to", ColumnType::Timestamp, ColumnMode::Nullable),
+        ];
+    }
+}
+
+impl TableSchema for PlaylistActionTableRow {
+    fn columns() -> Vec<Column> {
+        return vec![
+            column("commit_sha", ColumnType::String, ColumnMode::Required),
+            column("timestamp", ColumnType::Timestamp, ColumnMode::Required),
+            column("action_type", ColumnType::String, ColumnMode::Required),
+            column("playlist_id", ColumnType::String, ColumnMode::Required),
+            column("name", ColumnType::String, ColumnMode::Required),
+            column("previous_name", ColumnType::String, ColumnMode::Nullable),
+        ];
+    }
+}
+
+impl TableSchema for PlaylistTableRow {
+    fn columns() -> Vec<Column> {
+        return vec![
+            column("id", ColumnType::String, ColumnMode::Required),
+            column("name", ColumnType::String, ColumnMode::Required),
+            column("first_seen_at", ColumnType::Timestamp, ColumnMode::Required),
+            column("last_seen_at", ColumnType::Timestamp, ColumnMode::Required),
+        ];
+    }
+
+    // Widen the period the playlist has been seen in across runs
+    fn merge_rule(column_name: &str) -> MergeRule {
+        return match column_name {
+            "first_seen_at" => MergeRule::KeepMin,
+            "last_seen_at" => MergeRule::KeepMax,
+            _ => MergeRule::Replace,
+        };
+    }
+}
+
+impl TableSchema for ConversionErrorTableRow {
+    fn columns() -> Vec<Column> {
+        return vec![
+            column("commit_sha", ColumnType::String, ColumnMode::Required),
+            column("timestamp", ColumnType::Timestamp, ColumnMode::Required),
+            column("message", ColumnType::String, ColumnMode::Required),
+            column("kind", ColumnType::String, ColumnMode::Required),
+            column("filename", ColumnType::String, ColumnMode::Nullable),
+            column("reason", ColumnType::String, ColumnMode::Required),
+        ];
+    }
+}
+
+impl TableSchema for ArtistTableRow {
+    fn columns() -> Vec<Column> {
+        return vec![
+            column("id", ColumnType::String, ColumnMode::Required),
+            column("name", ColumnType::String, ColumnMode::Required),
+        ];
+    }
+}
+
+fn column(name: &'static str, column_type: ColumnType, mode: ColumnMode) -> Column {
+    return Column {
+        name,
+        column_type,
+        mode,
+    };
+}
+
+pub fn actions_to_table_rows(actions: Actions) -> TableRows {
+    let conversion_errors = conversion_error_to_table_rows(&actions.conversion_errors);
+    let track_history = track_related_action_to_track_history_rows(&actions.track_related_actions);
+    let (playlist_actions, playlists) = playlist_action_to_table_rows(&actions);
+    let (action_rows, tracks, artists) =
+        track_related_action_to_table_rows(actions.track_related_actions);
+    return TableRows {
+        actions: action_rows,
+        tracks,
+        artists,
+        track_history,
+        playlist_actions,
+        playlists,
+        conversion_errors,
+    };
+}
+
 pub fn track_related_action_to_table_rows(
     actions: Vec<TrackRelatedAction>,
 ) -> (Vec<ActionTableRow>, Vec<TrackTableRow>, Vec<ArtistTableRow>) {
@@ -37,11 +344,15 @@ pub fn track_related_action_to_table_rows(
         match action.action_type {
             TrackRelatedActionType::Addition => {
                 action_rows.push(ActionTableRow {
+                    commit_sha: action.commit_sha.to_string(),
                     timestamp: action.datetime.to_rfc3339(),
                     action_type: "addition".to_string(),
                     source_playlist_id: action.source_playlist_id.clone(),
                     destination_playlist_id: action.destination_playlist_id.clone(),
                     track_id: action.track.id.to_string(),
+                    source_position: action.source_position,
+                    destination_position: action.destination_position,
+                    added_at: action.track.added_at.map(|added_at| added_at.to_rfc3339()),
                 });
                 track_id_to_track_row.insert(
                     action.track.id.to_string(),
@@ -54,20 +365,41 @@ pub fn track_related_action_to_table_rows(
             }
             TrackRelatedActionType::Removal => {
                 action_rows.push(ActionTableRow {
+                    commit_sha: action.commit_sha.to_string(),
                     timestamp: action.datetime.to_rfc3339(),
                     action_type: "removal".to_string(),
                     source_playlist_id: action.source_playlist_id.clone(),
                     destination_playlist_id: action.destination_playlist_id.clone(),
                     track_id: action.track.id.to_string(),
+                    source_position: action.source_position,
+                    destination_position: action.destination_position,
+                    added_at: action.track.added_at.map(|added_at| added_at.to_rfc3339()),
                 });
             }
             TrackRelatedActionType::Transfer => {
                 action_rows.push(ActionTableRow {
+                    commit_sha: action.commit_sha.to_string(),
                     timestamp: action.datetime.to_rfc3339(),
                     action_type: "transfer".to_string(),
                     source_playlist_id: action.source_playlist_id.clone(),
                     destination_playlist_id: action.destination_playlist_id.clone(),
                     track_id: action.track.id.to_string(),
+                    source_position: action.source_position,
+                    destination_position: action.destination_position,
+                    added_at: action.track.added_at.map(|added_at| added_at.to_rfc3339()),
+                });
+            }
+            TrackRelatedActionType::Reorder => {
+                action_rows.push(ActionTableRow {
+                    commit_sha: action.commit_sha.to_string(),
+                    timestamp: action.datetime.to_rfc3339(),
+                    action_type: "reorder".to_string(),
+                    source_playlist_id: action.source_playlist_id.clone(),
+                    destination_playlist_id: action.destination_playlist_id.clone(),
+                    track_id: action.track.id.to_string(),
+                    source_position: action.source_position,
+                    destination_position: action.destination_position,
+                    added_at: action.track.added_at.map(|added_at| added_at.to_rfc3339()),
                 });
             }
             TrackRelatedActionType::Modification => {
@@ -83,18 +415,150 @@ pub fn track_related_action_to_table_rows(
         }
     }
 
-    let track_rows = track_id_to_track_row
-        .into_iter()
-        .map(|(_id, track)| track)
-        .collect();
-    let artist_rows = artist_id_to_artist_row
-        .into_iter()
-        .map(|(_id, artist)| artist)
-        .collect();
+    let track_rows = track_id_to_track_row.into_values().collect();
+    let artist_rows = artist_id_to_artist_row.into_values().collect();
 
     return (action_rows, track_rows, artist_rows);
 }
 
+// Builds the versions of the tracks from the additions and modifications in order.
+// A version is valid from the action that introduced it until the next version of the track.
+pub fn track_related_action_to_track_history_rows(
+    actions: &[TrackRelatedAction],
+) -> Vec<TrackHistoryTableRow> {
+    let mut history_rows: Vec<TrackHistoryTableRow> = vec![];
+    // Index of the current version of each track in history_rows
+    let mut track_id_to_current_index = HashMap::new();
+
+    for action in actions {
+        if !matches!(
+            action.action_type,
+            TrackRelatedActionType::Addition | TrackRelatedActionType::Modification
+        ) {
+            continue;
+        }
+        let track_row = track_to_track_table_row(&action.track);
+        let valid_from = action.datetime.to_rfc3339();
+        if let Some(&index) = track_id_to_current_index.get(&track_row.id) {
+            let current_row: &mut TrackHistoryTableRow = &mut history_rows[index];
+            // Adding the same track to another playlist does not make a new version
+            if current_row.name == track_row.name && current_row.artist_ids == track_row.artist_ids
+            {
+                continue;
+            }
+            current_row.valid_to = Some(valid_from.to_string());
+        }
+        track_id_to_current_index.insert(track_row.id.to_string(), history_rows.len());
+        history_rows.push(TrackHistoryTableRow {
+            id: track_row.id,
+            name: track_row.name,
+            artist_ids: track_row.artist_ids,
+            valid_from,
+            valid_to: None,
+        });
+    }
+
+    return history_rows;
+}
+
+// Builds the playlist action rows and the playlist rows.
+// A playlist is seen in its own actions and in the track actions on it.
+pub fn playlist_action_to_table_rows(
+    actions: &Actions,
+) -> (Vec<PlaylistActionTableRow>, Vec<PlaylistTableRow>) {
+    let action_rows = actions
+        .playlist_actions
+        .iter()
+        .map(playlist_action_to_playlist_action_table_row)
+        .collect();
+
+    // (playlist ID, playlist name, datetime)
+    let mut sightings = vec![];
+    for action in &actions.playlist_actions {
+        sightings.push((&action.playlist_id, &action.name, action.datetime));
+    }
+    for action in &actions.track_related_actions {
+        if let (Some(id), Some(name)) = (&action.source_playlist_id, &action.source_playlist_name) {
+            sightings.push((id, name, action.datetime));
+        }
+        if let (Some(id), Some(name)) = (
+            &action.destination_playlist_id,
+            &action.destination_playlist_name,
+        ) {
+            sightings.push((id, name, action.datetime));
+        }
+    }
+    // The sort is stable, so the later action wins among the actions in the same commit
+    sightings.sort_by_key(|(_id, _name, datetime)| *datetime);
+
+    let mut playlist_id_to_playlist_row: HashMap<&String, PlaylistTableRow> = HashMap::new();
+    for (id, name, datetime) in sightings {
+        let seen_at = datetime.to_rfc3339();
+        match playlist_id_to_playlist_row.get_mut(id) {
+            Some(playlist_row) => {
+                playlist_row.name = name.to_string();
+                playlist_row.last_seen_at = seen_at;
+            }
+            None => {
+                playlist_id_to_playlist_row.insert(
+                    id,
+                    PlaylistTableRow {
+                        id: id.to_string(),
+                        name: name.to_string(),
+                        first_seen_at: seen_at.to_string(),
+                        last_seen_at: seen_at,
+                    },
+                );
+            }
+        }
+    }
+    let playlist_rows = playlist_id_to_playlist_row.into_values().collect();
+
+    return (action_rows, playlist_rows);
+}
+
+fn playlist_action_to_playlist_action_table_row(action: &PlaylistAction) -> PlaylistActionTableRow {
+    let action_type = match action.action_type {
+        PlaylistActionType::Creation => "creation",
+        PlaylistActionType::Deletion => "deletion",
+        PlaylistActionType::Modification => match &action.previous_name {
+            Some(previous_name) if *previous_name != action.name => "rename",
+            _ => "modification",
+        },
+    };
+    return PlaylistActionTableRow {
+        commit_sha: action.commit_sha.to_string(),
+        timestamp: action.datetime.to_rfc3339(),
+        action_type: action_type.to_string(),
+        playlist_id: action.playlist_id.to_string(),
+        name: action.name.to_string(),
+        previous_name: action.previous_name.clone(),
+    };
+}
+
+pub fn conversion_error_to_table_rows(errors: &[ConversionError]) -> Vec<ConversionErrorTableRow> {
+    return errors
+        .iter()
+        .map(|error| {
+            let (kind, filename) = match &error.kind {
+                ConversionErrorKind::MissingSnapshot => ("missing_snapshot", None),
+                ConversionErrorKind::InvalidSnapshot { filename, .. } => {
+                    ("invalid_snapshot", Some(filename.to_string()))
+                }
+                ConversionErrorKind::NoTrackChange => ("no_track_change", None),
+            };
+            ConversionErrorTableRow {
+                commit_sha: error.commit_sha.to_string(),
+                timestamp: error.datetime.to_rfc3339(),
+                message: error.message.to_string(),
+                kind: kind.to_string(),
+                filename,
+                reason: error.kind.to_string(),
+            }
+        })
+        .collect();
+}
+
 fn track_to_track_table_row(track: &Track) -> TrackTableRow {
     return TrackTableRow {
         id: track.id.to_string(),
@@ -104,6 +568,10 @@ fn track_to_track_table_row(track: &Track) -> TrackTableRow {
             .iter()
             .map(|artist| artist.id.to_string())
             .collect(),
+        album_id: track.album.as_ref().map(|album| album.id.to_string()),
+        album_name: track.album.as_ref().map(|album| album.name.to_string()),
+        duration_ms: track.duration_ms,
+        popularity: track.popularity,
     };
 }
 
@@ -113,3 +581,13 @@ fn artist_to_artist_table_row(artist: &Artist) -> ArtistTableRow {
         name: artist.name.to_string(),
     };
 }
+
+fn content_hash(fields: &[&str]) -> String {
+    let mut hasher = Sha256::new();
+    for field in fields {
+        hasher.update(field.as_bytes());
+        // Separate the fields so that ["ab", "c"] and ["a", "bc"] differ
+        hasher.update([0]);
+    }
+    return format!("{:x}", hasher.finalize());
+}
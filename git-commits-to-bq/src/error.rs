@@ -0,0 +1,81 @@
+use std::error::Error;
+use std::fmt;
+
+use crate::spotify_log::defs::{ConversionError, ConversionErrorKind};
+
+// The classes of failure of a run, each of which exits with its own code
+#[derive(Debug)]
+pub enum AppError {
+    // Every missing or invalid setting, so that they can be fixed at once
+    Config(Vec<String>),
+    // Reading or writing the checkpoint or the conversion report
+    LocalState(Box<dyn Error>),
+    // Fetching the commits through the GitHub API
+    Github(Box<dyn Error>),
+    // Reading the commits from a local clone
+    Git(Box<dyn Error>),
+    // The number of commits skipped because a playlist snapshot could not be parsed
+    SnapshotParse(usize),
+    // The number of commits skipped for other reasons
+    Conversion(usize),
+    // Preparing or writing the tables in the sink
+    Sink(Box<dyn Error>),
+}
+
+impl AppError {
+    pub fn exit_code(&self) -> i32 {
+        return match self {
+            AppError::Config(_) => 2,
+            AppError::LocalState(_) => 3,
+            AppError::Github(_) => 4,
+            AppError::Git(_) => 5,
+            AppError::SnapshotParse(_) => 6,
+            AppError::Conversion(_) => 7,
+            AppError::Sink(_) => 8,
+        };
+    }
+
+    // Returns None when every commit was converted.
+    // Commits with an unparsable snapshot take precedence, since they usually mean a broken download.
+    pub fn from_conversion_errors(errors: &[ConversionError]) -> Option<AppError> {
+        if errors.is_empty() {
+            return None;
+        }
+        let snapshot_error_count = errors
+            .iter()
+            .filter(|error| matches!(error.kind, ConversionErrorKind::InvalidSnapshot { .. }))
+            .count();
+        if snapshot_error_count > 0 {
+            return Some(AppError::SnapshotParse(snapshot_error_count));
+        }
+        return Some(AppError::Conversion(errors.len()));
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return match self {
+            AppError::Config(problems) => {
+                write!(f, "Invalid configuration:")?;
+                for problem in problems {
+                    write!(f, "\n  - {}", problem)?;
+                }
+                Ok(())
+            }
+            AppError::LocalState(e) => write!(f, "Error with the local state: {}", e),
+            AppError::Github(e) => write!(f, "Error fetching the commits from GitHub: {}", e),
+            AppError::Git(e) => write!(f, "Error reading the local clone: {}", e),
+            AppError::SnapshotParse(count) => write!(
+                f,
+                "{} commits were skipped because of an unparsable snapshot",
+                count
+            ),
+            AppError::Conversion(count) => {
+                write!(f, "{} commits could not be converted", count)
+            }
+            AppError::Sink(e) => write!(f, "Error writing the tables: {}", e),
+        };
+    }
+}
+
+impl Error for AppError {}
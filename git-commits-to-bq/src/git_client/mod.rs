@@ -0,0 +1,313 @@
+use chrono::prelude::*;
+use git2::{Oid, Repository, Sort};
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use tokio::task;
+
+use crate::commit_source::CommitSource;
+use crate::github_client::defs;
+
+mod util;
+
+// The error of a libgit2 call, which is sent back from the blocking thread
+type BlockingError = Box<dyn Error + Send + Sync>;
+
+// libgit2 calls block, so they run on the blocking thread pool instead of the async workers.
+// A Repository cannot be shared between threads, so the calls take turns on it.
+pub struct GitClient {
+    repo: Arc<Mutex<Repository>>,
+    branch: Option<String>, // A branch, or anything else naming a commit, e.g. a SHA
+}
+
+impl CommitSource for GitClient {
+    // Walking the whole history of a clone is cheap, so `since` is left to the caller
+    async fn fetch_commit_shas(
+        &self,
+        _since: Option<DateTime<Utc>>,
+    ) -> Result<Vec<String>, Box<dyn Error>> {
+        let branch = self.branch.clone();
+        return self
+            .run_blocking(move |repo| read_commit_shas(repo, branch.as_deref()))
+            .await;
+    }
+
+    async fn fetch_commit_by_sha(&self, sha: &str) -> Result<defs::Commit, Box<dyn Error>> {
+        let sha = sha.to_string();
+        return self.run_blocking(move |repo| read_commit(repo, &sha)).await;
+    }
+}
+
+impl GitClient {
+    async fn run_blocking<T, F>(&self, f: F) -> Result<T, Box<dyn Error>>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Repository) -> Result<T, BlockingError> + Send + 'static,
+    {
+        let repo = Arc::clone(&self.repo);
+        let result = task::spawn_blocking(move || {
+            let repo = repo
+                .lock()
+                .map_err(|_| "A libgit2 call panicked while reading the repository")?;
+            f(&repo)
+        })
+        .await?;
+        return result.map_err(|e| -> Box<dyn Error> { e });
+    }
+}
+
+fn read_commit_shas(repo: &Repository, branch: Option<&str>) -> Result<Vec<String>, BlockingError> {
+    let mut revwalk = repo.revwalk()?;
+    // Walk from the oldest commit to match the order of the GitHub API after reversing
+    revwalk.set_sorting(Sort::TOPOLOGICAL | Sort::TIME | Sort::REVERSE)?;
+    match branch {
+        Some(branch) => {
+            let commit = repo
+                .revparse_single(branch)
+                .and_then(|object| object.peel_to_commit())
+                .map_err(|e| format!("The branch {} is not in the clone: {}", branch, e))?;
+            revwalk.push(commit.id())?;
+        }
+        None => revwalk.push_head()?,
+    }
+    let shas = revwalk
+        .map(|oid| oid.map(|oid| oid.to_string()))
+        .collect::<Result<Vec<String>, git2::Error>>()?;
+    return Ok(shas);
+}
+
+fn read_commit(repo: &Repository, sha: &str) -> Result<defs::Commit, BlockingError> {
+    let commit = repo.find_commit(Oid::from_str(sha)?)?;
+    let committer = commit.committer();
+    let committer_name = committer.name().unwrap_or_default().to_string();
+    let message = commit.message().unwrap_or_default().to_string();
+    let datetime = Utc
+        .timestamp_opt(committer.when().seconds(), 0)
+        .single()
+        .ok_or("The committer time is out of range")?;
+
+    // The first commit is not a Spotify log, so just ignore it
+    if commit.parent_count() == 0 {
+        return Ok(defs::Commit {
+            sha: sha.to_string(),
+            committer_name,
+            message,
+            datetime,
+            files: vec![],
+        });
+    }
+    let parent_tree = commit.parent(0)?.tree()?;
+    let tree = commit.tree()?;
+    let diff = repo.diff_tree_to_tree(Some(&parent_tree), Some(&tree), None)?;
+    let mut files = vec![];
+    for delta in diff.deltas() {
+        let diff_type = util::delta_to_diff_type(delta.status());
+        let path = delta
+            .new_file()
+            .path()
+            .or_else(|| delta.old_file().path())
+            .ok_or("The diff has no file path")?;
+        let before = match diff_type {
+            defs::DiffType::Addition => String::from(""),
+            _ => read_blob(repo, delta.old_file().id())?,
+        };
+        let after = match diff_type {
+            defs::DiffType::Deletion => String::from(""),
+            _ => read_blob(repo, delta.new_file().id())?,
+        };
+        let commit_file = defs::CommitFile {
+            filename: path.to_string_lossy().to_string(),
+            diff_type,
+            before,
+            after,
+        };
+        files.push(commit_file);
+    }
+
+    return Ok(defs::Commit {
+        sha: sha.to_string(),
+        committer_name,
+        message,
+        datetime,
+        files,
+    });
+}
+
+fn read_blob(repo: &Repository, oid: Oid) -> Result<String, BlockingError> {
+    let blob = repo.find_blob(oid)?;
+    // Decoded like the API client decodes a response body, so both sources give the same content
+    let content = String::from_utf8_lossy(blob.content()).to_string();
+    return Ok(content);
+}
+
+pub fn new(repo_path: &str, branch: Option<String>) -> Result<GitClient, Box<dyn Error>> {
+    return Ok(GitClient {
+        repo: Arc::new(Mutex::new(Repository::open(repo_path)?)),
+        branch,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use git2::{Signature, Time};
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::process;
+
+    use super::*;
+
+    // A repository in the temporary directory, removed when the test ends
+    struct FixtureRepo {
+        dir: PathBuf,
+        repo: Repository,
+    }
+
+    impl FixtureRepo {
+        // Writes the files, or deletes those without content, and commits them at the time
+        fn commit(&self, files: &[(&str, Option<&str>)], message: &str, seconds: i64) -> String {
+            let mut index = self.repo.index().unwrap();
+            for (path, content) in files {
+                match content {
+                    Some(content) => {
+                        fs::write(self.dir.join(path), content).unwrap();
+                        index.add_path(Path::new(path)).unwrap();
+                    }
+                    None => {
+                        fs::remove_file(self.dir.join(path)).unwrap();
+                        index.remove_path(Path::new(path)).unwrap();
+                    }
+                }
+            }
+            index.write().unwrap();
+            let tree = self.repo.find_tree(index.write_tree().unwrap()).unwrap();
+            let signature = Signature::new(
+                "GitHub Actions",
+                "actions@example.com",
+                &Time::new(seconds, 0),
+            )
+            .unwrap();
+            let parents = match self.repo.head() {
+                Ok(head) => vec![head.peel_to_commit().unwrap()],
+                Err(_) => vec![],
+            };
+            let parents: Vec<&git2::Commit> = parents.iter().collect();
+            let oid = self
+                .repo
+                .commit(
+                    Some("HEAD"),
+                    &signature,
+                    &signature,
+                    message,
+                    &tree,
+                    &parents,
+                )
+                .unwrap();
+            return oid.to_string();
+        }
+    }
+
+    impl Drop for FixtureRepo {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn fixture_repo(name: &str) -> FixtureRepo {
+        let dir =
+            std::env::temp_dir().join(format!("git-commits-to-bq-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let repo = Repository::init(&dir).unwrap();
+        return FixtureRepo { dir, repo };
+    }
+
+    fn find_file<'a>(commit: &'a defs::Commit, filename: &str) -> &'a defs::CommitFile {
+        return commit
+            .files
+            .iter()
+            .find(|file| file.filename == filename)
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn fetch_commit_shas_returns_oldest_first() {
+        let fixture = fixture_repo("shas");
+        let shas = vec![
+            fixture.commit(&[("README.md", Some("log"))], "init", 1_570_000_000),
+            fixture.commit(&[("a.json", Some("1"))], "second", 1_570_000_100),
+            fixture.commit(&[("a.json", Some("2"))], "third", 1_570_000_200),
+        ];
+        let git_client = new(fixture.dir.to_str().unwrap(), None).unwrap();
+
+        assert_eq!(git_client.fetch_commit_shas(None).await.unwrap(), shas);
+    }
+
+    #[tokio::test]
+    async fn fetch_commit_shas_walks_the_branch() {
+        let fixture = fixture_repo("branch");
+        let shas = vec![
+            fixture.commit(&[("README.md", Some("log"))], "init", 1_570_000_000),
+            fixture.commit(&[("a.json", Some("1"))], "second", 1_570_000_100),
+        ];
+        let head = fixture.repo.head().unwrap().peel_to_commit().unwrap();
+        fixture.repo.branch("backup", &head, false).unwrap();
+        fixture.commit(&[("a.json", Some("2"))], "third", 1_570_000_200);
+        let git_client = new(fixture.dir.to_str().unwrap(), Some("backup".to_string())).unwrap();
+
+        assert_eq!(git_client.fetch_commit_shas(None).await.unwrap(), shas);
+    }
+
+    #[tokio::test]
+    async fn fetch_commit_by_sha_reads_the_diff() {
+        let fixture = fixture_repo("diff");
+        let first_sha = fixture.commit(&[("README.md", Some("log"))], "init", 1_570_000_000);
+        fixture.commit(
+            &[("a.json", Some("a1")), ("b.json", Some("b1"))],
+            "add",
+            1_570_000_100,
+        );
+        let change_sha = fixture.commit(
+            &[
+                ("a.json", Some("a2")),
+                ("b.json", None),
+                ("c.json", Some("c1")),
+            ],
+            ":pencil2: change",
+            1_570_000_200,
+        );
+        let git_client = new(fixture.dir.to_str().unwrap(), None).unwrap();
+
+        // The first commit has no parent to diff against
+        let first_commit = git_client.fetch_commit_by_sha(&first_sha).await.unwrap();
+        assert!(first_commit.files.is_empty());
+
+        let commit = git_client.fetch_commit_by_sha(&change_sha).await.unwrap();
+        assert_eq!(commit.sha, change_sha);
+        assert_eq!(commit.committer_name, "GitHub Actions");
+        assert_eq!(commit.message, ":pencil2: change");
+        assert_eq!(commit.datetime.timestamp(), 1_570_000_200);
+        assert_eq!(commit.files.len(), 3);
+
+        let modified = find_file(&commit, "a.json");
+        assert!(matches!(modified.diff_type, defs::DiffType::Modification));
+        assert_eq!(modified.before, "a1");
+        assert_eq!(modified.after, "a2");
+
+        let deleted = find_file(&commit, "b.json");
+        assert!(matches!(deleted.diff_type, defs::DiffType::Deletion));
+        assert_eq!(deleted.before, "b1");
+        assert_eq!(deleted.after, "");
+
+        let added = find_file(&commit, "c.json");
+        assert!(matches!(added.diff_type, defs::DiffType::Addition));
+        assert_eq!(added.before, "");
+        assert_eq!(added.after, "c1");
+    }
+
+    #[test]
+    fn read_blob_replaces_invalid_utf8() {
+        let fixture = fixture_repo("blob");
+        let oid = fixture.repo.blob(b"caf\xe9").unwrap();
+
+        assert_eq!(read_blob(&fixture.repo, oid).unwrap(), "caf\u{fffd}");
+    }
+}
@@ -0,0 +1,12 @@
+use git2::Delta;
+
+use crate::github_client::defs::DiffType;
+
+pub fn delta_to_diff_type(delta: Delta) -> DiffType {
+    return match delta {
+        Delta::Added => DiffType::Addition,
+        Delta::Deleted => DiffType::Deletion,
+        Delta::Modified => DiffType::Modification,
+        _ => DiffType::Unknown,
+    };
+}
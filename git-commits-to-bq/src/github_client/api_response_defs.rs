@@ -33,8 +33,12 @@ pub struct CommitItemParent {
 
 #[derive(Serialize, Deserialize)]
 pub struct CommitItemFile {
-    pub filename: String, // e.g. "playlists/1.json"
-    pub additions: u16,
-    pub deletions: u16,
-    pub status: String, // "added" | "removed" | "modified"
+    pub filename: String,                  // e.g. "playlists/1.json"
+    pub status: String,                    // "added" | "removed" | "modified" | "renamed" | ...
+    pub previous_filename: Option<String>, // Set when renamed
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ErrorResponse {
+    pub message: String,
 }
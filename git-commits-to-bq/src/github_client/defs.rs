@@ -1,5 +1,7 @@
 use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
 
+#[derive(Serialize, Deserialize)]
 pub enum DiffType {
     Addition,
     Deletion,
@@ -7,6 +9,7 @@ pub enum DiffType {
     Unknown,
 }
 
+#[derive(Serialize, Deserialize)]
 pub struct Commit {
     pub sha: String,
     pub committer_name: String,
@@ -15,11 +18,10 @@ pub struct Commit {
     pub files: Vec<CommitFile>,
 }
 
+#[derive(Serialize, Deserialize)]
 pub struct CommitFile {
     pub filename: String, // e.g. "playlists/1.json"
     pub diff_type: DiffType,
-    pub added_line_count: u16,
-    pub deleted_line_count: u16,
     pub before: String,
     pub after: String,
 }
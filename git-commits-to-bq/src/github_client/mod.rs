@@ -1,142 +1,177 @@
 use chrono::prelude::*;
-use github_rs::client::{Executor, Github};
+use futures::future;
+use futures::stream::{self, Stream, TryStreamExt};
+use reqwest::header::{HeaderMap, ACCEPT, AUTHORIZATION, USER_AGENT};
+use reqwest::Url;
 use std::error::Error;
+use std::time::Duration;
+use tokio::sync::{Mutex, Semaphore};
+
+use crate::commit_source::CommitSource;
 
 mod api_response_defs;
 pub mod defs;
+mod rate_limit;
 mod util;
 
+pub const DEFAULT_API_URL: &str = "https://api.github.com";
+const JSON_MEDIA_TYPE: &str = "application/vnd.github.v3+json";
+// Makes the contents endpoint return the file itself instead of its metadata
+const RAW_MEDIA_TYPE: &str = "application/vnd.github.v3.raw";
+// The API rejects requests without a User-Agent
+const USER_AGENT_VALUE: &str = "git-commits-to-bq";
+// Transient failures (network errors and 5xx) are retried with exponential backoff
+const MAX_ATTEMPTS: u32 = 5;
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
 pub struct GithubClient {
-    client: Github,
+    client: reqwest::Client,
+    api_url: String, // e.g. "https://github.example.com/api/v3" for GitHub Enterprise
+    token: String,
     repo_owner: String,
     repo_name: String,
+    // Bounds the raw file downloads across the commits fetched at once
+    download_permits: Semaphore,
+    commit_filter: CommitFilter,
+    // Set when a response used up the rate limit, so that no request is sent until it resets
+    rate_limited_until: Mutex<Option<DateTime<Utc>>>,
 }
 
-impl GithubClient {
-    pub fn fetch_commit_shas(&self) -> Vec<String> {
-        let per_page = 100; // The max limit of the API
+// A successful response, read in full so that a connection dropped mid-body is retried too
+struct ApiResponse {
+    headers: HeaderMap,
+    body: String,
+}
 
-        // The page number starts from 1, not 0
-        let mut shas = self.fetch_commit_shas_recursively(per_page, 1);
+// Narrows down the commits listed by the API
+#[derive(Clone, Default)]
+pub struct CommitFilter {
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    pub branch: Option<String>, // The default branch when None
+}
+
+impl CommitSource for GithubClient {
+    async fn fetch_commit_shas(
+        &self,
+        since: Option<DateTime<Utc>>,
+    ) -> Result<Vec<String>, Box<dyn Error>> {
+        let mut shas = self.commit_shas(since).try_collect::<Vec<String>>().await?;
 
         // Commits are in descending order, so reverse the vec to make it ascending
         shas.reverse();
-        return shas;
+        return Ok(shas);
     }
 
-    fn fetch_commit_shas_recursively(&self, per_page: u8, page: u8) -> Vec<String> {
-        let commits_endpoint = format!(
-            "repos/{}/{}/commits?per_page={}&page={}",
-            &self.repo_owner, &self.repo_name, per_page, page
-        );
-        let res = self
-            .client
-            .get()
-            .custom_endpoint(&commits_endpoint)
-            .execute::<Vec<api_response_defs::CommitMetadata>>();
-        // When no item is found on the page, the API returns
-        // an empty array with status code 200 instead of 404.
-        let shas = match res {
-            Ok((_headers, _status, data)) => data
-                .unwrap_or_default()
-                .iter()
-                .map(|metadata| metadata.sha.to_string())
-                .collect(),
-            Err(_e) => Vec::<String>::new(),
-        };
-        // If the previous page was the last page, stop the recursive calls.
-        if shas.is_empty() {
-            return shas;
-        }
-        let shas_in_following_pages = self.fetch_commit_shas_recursively(per_page, page + 1);
-        return [shas, shas_in_following_pages].concat();
+    async fn fetch_commit_by_sha(&self, sha: &str) -> Result<defs::Commit, Box<dyn Error>> {
+        let url = self.url(&format!(
+            "repos/{}/{}/commits/{}",
+            self.repo_owner, self.repo_name, sha
+        ));
+        let resp = self.get(&url, JSON_MEDIA_TYPE).await?;
+        let commit_response = serde_json::from_str::<api_response_defs::CommitItem>(&resp.body)?;
+        return self.commit_response_to_commit(&commit_response).await;
     }
+}
 
-    pub async fn fetch_commit_by_sha(&self, sha: &str) -> Result<defs::Commit, Box<dyn Error>> {
-        let res = self
-            .client
-            .get()
-            .repos()
-            .owner(&self.repo_owner)
-            .repo(&self.repo_name)
-            .commits()
-            .sha(sha)
-            .execute::<api_response_defs::CommitItem>();
-        match res {
-            Ok((_headers, _status, data)) => match data {
-                Some(commit_response) => {
-                    return Ok(self.commit_response_to_commit(&commit_response).await)
-                }
-                None => {
-                    return Err(From::from(
-                        "Failed to parse the response from the API endpoint /repos/{owner}/{repo}/commits/{ref}",
-                    ));
-                }
-            },
-            Err(e) => return Err(Box::new(e)),
+impl GithubClient {
+    // Yields the commit SHAs in descending order, following the pages by the Link header
+    pub fn commit_shas(
+        &self,
+        since: Option<DateTime<Utc>>,
+    ) -> impl Stream<Item = Result<String, Box<dyn Error>>> + '_ {
+        let first_page_url = self.first_commit_page_url(since);
+        return stream::try_unfold(Some(first_page_url), move |page_url| async move {
+            let page_url = match page_url {
+                Some(page_url) => page_url?,
+                None => return Ok(None),
+            };
+            let resp = self.get(page_url.as_str(), JSON_MEDIA_TYPE).await?;
+            let next_page_url = util::next_page_url(&resp.headers).map(|url| Ok(Url::parse(&url)?));
+            let metadata_list =
+                serde_json::from_str::<Vec<api_response_defs::CommitMetadata>>(&resp.body)?;
+            let shas = metadata_list.into_iter().map(|metadata| Ok(metadata.sha));
+            return Ok::<_, Box<dyn Error>>(Some((stream::iter(shas), next_page_url)));
+        })
+        .try_flatten();
+    }
+
+    // The later of `since` and the one of the filter applies; None is less than any Some
+    fn first_commit_page_url(&self, since: Option<DateTime<Utc>>) -> Result<Url, Box<dyn Error>> {
+        let mut params = vec![("per_page", "100".to_string())]; // The max limit of the API
+        if let Some(since) = self.commit_filter.since.max(since) {
+            params.push(("since", since.to_rfc3339_opts(SecondsFormat::Secs, true)));
+        }
+        if let Some(until) = self.commit_filter.until {
+            params.push(("until", until.to_rfc3339_opts(SecondsFormat::Secs, true)));
+        }
+        if let Some(branch) = &self.commit_filter.branch {
+            params.push(("sha", branch.to_string()));
         }
+        let url = self.url(&format!(
+            "repos/{}/{}/commits",
+            self.repo_owner, self.repo_name
+        ));
+        return Ok(Url::parse_with_params(&url, &params)?);
     }
 
     async fn commit_response_to_commit(
         &self,
         commit_response: &api_response_defs::CommitItem,
-    ) -> defs::Commit {
+    ) -> Result<defs::Commit, Box<dyn Error>> {
+        let datetime = commit_response
+            .commit
+            .committer
+            .date
+            .parse::<DateTime<Utc>>()?;
+
         // The first commit is not a Spotify log, so just ignore it
         if commit_response.parents.is_empty() {
-            return defs::Commit {
+            return Ok(defs::Commit {
                 sha: commit_response.sha.to_string(),
                 committer_name: commit_response.commit.committer.name.to_string(),
                 message: commit_response.commit.message.to_string(),
-                datetime: commit_response
-                    .commit
-                    .committer
-                    .date
-                    .parse::<DateTime<Utc>>()
-                    .unwrap(),
+                datetime,
                 files: vec![],
-            };
+            });
         }
         let parent_sha = &commit_response.parents[0].sha;
-        let mut files = vec![];
-        for file_response in &commit_response.files {
-            let diff_type = util::status_to_diff_type(&file_response.status);
-            let before = match diff_type {
-                defs::DiffType::Addition => String::from(""),
-                _ => self
-                    .fetch_file_content(parent_sha, &file_response.filename)
-                    .await
-                    .unwrap_or(String::from("")),
-            };
-            let after = match diff_type {
-                defs::DiffType::Deletion => String::from(""),
-                _ => self
-                    .fetch_file_content(&commit_response.sha, &file_response.filename)
-                    .await
-                    .unwrap_or(String::from("")),
-            };
-            let commit_file = defs::CommitFile {
-                filename: file_response.filename.to_string(),
-                diff_type,
-                added_line_count: file_response.additions,
-                deleted_line_count: file_response.deletions,
-                before,
-                after,
-            };
-            files.push(commit_file);
-        }
+        let changes = util::file_changes(&commit_response.files);
+        // Download the files, and the before and after contents of each, at the same time
+        let files =
+            future::try_join_all(changes.into_iter().map(|(filename, diff_type)| async move {
+                let before = async {
+                    return match diff_type {
+                        defs::DiffType::Addition => Ok(String::from("")),
+                        _ => self.fetch_file_content(parent_sha, filename).await,
+                    };
+                };
+                let after = async {
+                    return match diff_type {
+                        defs::DiffType::Deletion => Ok(String::from("")),
+                        _ => {
+                            self.fetch_file_content(&commit_response.sha, filename)
+                                .await
+                        }
+                    };
+                };
+                let (before, after) = future::try_join(before, after).await?;
+                return Ok::<defs::CommitFile, Box<dyn Error>>(defs::CommitFile {
+                    filename: filename.to_string(),
+                    diff_type,
+                    before,
+                    after,
+                });
+            }))
+            .await?;
 
-        return defs::Commit {
+        return Ok(defs::Commit {
             sha: commit_response.sha.to_string(),
             committer_name: commit_response.commit.committer.name.to_string(),
             message: commit_response.commit.message.to_string(),
-            datetime: commit_response
-                .commit
-                .committer
-                .date
-                .parse::<DateTime<Utc>>()
-                .unwrap(),
+            datetime,
             files,
-        };
+        });
     }
 
     async fn fetch_file_content(
@@ -144,19 +179,121 @@ impl GithubClient {
         commit_sha: &str,
         path: &str,
     ) -> Result<String, Box<dyn Error>> {
-        let url = format!(
-            "https://github.com/{}/{}/raw/{}/{}",
-            self.repo_owner, self.repo_name, commit_sha, path
-        );
-        let content = reqwest::get(&url).await?.text().await?;
-        return Ok(content);
+        let _permit = self.download_permits.acquire().await?;
+        let url = self.url(&format!(
+            "repos/{}/{}/contents/{}?ref={}",
+            self.repo_owner, self.repo_name, path, commit_sha
+        ));
+        let resp = self.get(&url, RAW_MEDIA_TYPE).await?;
+        return Ok(resp.body);
+    }
+
+    fn url(&self, path: &str) -> String {
+        return format!("{}/{}", self.api_url, path);
+    }
+
+    // Fails with the message from the API unless the status is a success.
+    // Waits out rate limits and retries transient failures, so it never returns partial data.
+    async fn get(&self, url: &str, media_type: &str) -> Result<ApiResponse, Box<dyn Error>> {
+        let mut attempt = 1;
+        let mut backoff = INITIAL_BACKOFF;
+        loop {
+            self.wait_for_rate_limit().await;
+            let result = self.send(url, media_type).await;
+            let (status, headers, body) = match result {
+                Ok(response) => response,
+                Err(e) if attempt < MAX_ATTEMPTS && util::is_transient(&e) => {
+                    println!("GET {} failed ({}); retrying in {:?}", url, e, backoff);
+                    tokio::time::sleep(backoff).await;
+                    attempt += 1;
+                    backoff *= 2;
+                    continue;
+                }
+                Err(e) => return Err(Box::new(e)),
+            };
+
+            if let Some(reset_at) = rate_limit::exhausted_until(&headers) {
+                *self.rate_limited_until.lock().await = Some(reset_at);
+            }
+            if status.is_success() {
+                return Ok(ApiResponse { headers, body });
+            }
+            // Waiting for a rate limit does not count as an attempt
+            if let Some(wait) = rate_limit::rejection_wait(status, &headers) {
+                println!("GET {} was rate limited; waiting {:?}", url, wait);
+                tokio::time::sleep(wait).await;
+                continue;
+            }
+            if status.is_server_error() && attempt < MAX_ATTEMPTS {
+                println!(
+                    "GET {} failed with status {}; retrying in {:?}",
+                    url, status, backoff
+                );
+                tokio::time::sleep(backoff).await;
+                attempt += 1;
+                backoff *= 2;
+                continue;
+            }
+
+            let message = match serde_json::from_str::<api_response_defs::ErrorResponse>(&body) {
+                Ok(error_response) => error_response.message,
+                Err(_e) => body,
+            };
+            return Err(From::from(format!(
+                "GET {} failed with status {}: {}",
+                url, status, message
+            )));
+        }
+    }
+
+    // Sends a single request and reads the whole body
+    async fn send(
+        &self,
+        url: &str,
+        media_type: &str,
+    ) -> Result<(reqwest::StatusCode, HeaderMap, String), reqwest::Error> {
+        let resp = self
+            .client
+            .get(url)
+            .header(AUTHORIZATION, format!("token {}", self.token))
+            .header(ACCEPT, media_type)
+            .header(USER_AGENT, USER_AGENT_VALUE)
+            .send()
+            .await?;
+        let status = resp.status();
+        let headers = resp.headers().clone();
+        let body = resp.text().await?;
+        return Ok((status, headers, body));
+    }
+
+    async fn wait_for_rate_limit(&self) {
+        let rate_limited_until = *self.rate_limited_until.lock().await;
+        if let Some(reset_at) = rate_limited_until {
+            if reset_at > Utc::now() {
+                let wait = rate_limit::duration_until(reset_at);
+                println!("The GitHub rate limit is used up; waiting {:?}", wait);
+                tokio::time::sleep(wait).await;
+            }
+        }
     }
 }
 
-pub fn new(token: &str, repo_owner: &str, repo_name: &str) -> GithubClient {
+pub fn new(
+    api_url: &str,
+    token: &str,
+    repo_owner: &str,
+    repo_name: &str,
+    commit_filter: CommitFilter,
+    download_concurrency: usize,
+) -> GithubClient {
     return GithubClient {
-        client: Github::new(token).unwrap(),
+        client: reqwest::Client::new(),
+        api_url: api_url.trim_end_matches('/').to_string(),
+        token: token.to_string(),
         repo_owner: repo_owner.to_string(),
         repo_name: repo_name.to_string(),
+        download_permits: Semaphore::new(download_concurrency.max(1)),
+        commit_filter,
+        rate_limited_until: Mutex::new(None),
     };
 }
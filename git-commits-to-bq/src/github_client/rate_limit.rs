@@ -0,0 +1,103 @@
+use chrono::prelude::*;
+use reqwest::header::HeaderMap;
+use reqwest::StatusCode;
+use std::time::Duration;
+
+// Returns how long to wait before retrying a request rejected by a rate limit,
+// or None when the rejection is not caused by one
+pub fn rejection_wait(status: StatusCode, headers: &HeaderMap) -> Option<Duration> {
+    if status != StatusCode::FORBIDDEN && status != StatusCode::TOO_MANY_REQUESTS {
+        return None;
+    }
+    // Secondary rate limits tell how long to wait
+    if let Some(seconds) = header_number(headers, "retry-after") {
+        return Some(Duration::from_secs(seconds));
+    }
+    // The primary rate limit tells when it resets
+    if header_number(headers, "x-ratelimit-remaining") == Some(0) {
+        return reset_at(headers).map(duration_until);
+    }
+    // A 429 without the headers is still a rate limit, so wait for the documented minimum
+    if status == StatusCode::TOO_MANY_REQUESTS {
+        return Some(Duration::from_secs(60));
+    }
+    return None;
+}
+
+// Returns when the rate limit resets if the response used up the remaining requests
+pub fn exhausted_until(headers: &HeaderMap) -> Option<DateTime<Utc>> {
+    if header_number(headers, "x-ratelimit-remaining") != Some(0) {
+        return None;
+    }
+    return reset_at(headers);
+}
+
+pub fn duration_until(datetime: DateTime<Utc>) -> Duration {
+    // Wait a second longer, since the reset time is truncated to seconds
+    let seconds = (datetime - Utc::now()).num_seconds().max(0) as u64 + 1;
+    return Duration::from_secs(seconds);
+}
+
+fn reset_at(headers: &HeaderMap) -> Option<DateTime<Utc>> {
+    let reset_timestamp = header_number(headers, "x-ratelimit-reset")?;
+    return Utc.timestamp_opt(reset_timestamp as i64, 0).single();
+}
+
+fn header_number(headers: &HeaderMap, name: &str) -> Option<u64> {
+    return headers.get(name)?.to_str().ok()?.trim().parse().ok();
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::header::HeaderValue;
+
+    use super::*;
+
+    fn headers(entries: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in entries {
+            headers.insert(*name, HeaderValue::from_str(value).unwrap());
+        }
+        return headers;
+    }
+
+    #[test]
+    fn rejection_wait_follows_retry_after() {
+        let headers = headers(&[("retry-after", "30")]);
+        assert_eq!(
+            rejection_wait(StatusCode::FORBIDDEN, &headers),
+            Some(Duration::from_secs(30))
+        );
+    }
+
+    #[test]
+    fn rejection_wait_waits_until_the_reset() {
+        let reset_at = Utc::now().timestamp() + 120;
+        let headers = headers(&[
+            ("x-ratelimit-remaining", "0"),
+            ("x-ratelimit-reset", &reset_at.to_string()),
+        ]);
+        let wait = rejection_wait(StatusCode::FORBIDDEN, &headers).unwrap();
+        assert!(wait >= Duration::from_secs(119) && wait <= Duration::from_secs(121));
+    }
+
+    #[test]
+    fn rejection_wait_of_a_bare_429_is_a_minute() {
+        assert_eq!(
+            rejection_wait(StatusCode::TOO_MANY_REQUESTS, &HeaderMap::new()),
+            Some(Duration::from_secs(60))
+        );
+    }
+
+    #[test]
+    fn rejection_wait_ignores_other_rejections() {
+        // A 403 with requests remaining is a permission error
+        let permission_headers = headers(&[("x-ratelimit-remaining", "10")]);
+        assert_eq!(
+            rejection_wait(StatusCode::FORBIDDEN, &permission_headers),
+            None
+        );
+        let retry_headers = headers(&[("retry-after", "30")]);
+        assert_eq!(rejection_wait(StatusCode::NOT_FOUND, &retry_headers), None);
+    }
+}
@@ -1,10 +1,117 @@
+use reqwest::header::{HeaderMap, LINK};
+
+use crate::github_client::api_response_defs::CommitItemFile;
 use crate::github_client::defs::DiffType;
 
 pub fn status_to_diff_type(status: &str) -> DiffType {
     return match status {
-        "added" => DiffType::Addition,
+        "added" | "copied" => DiffType::Addition,
         "removed" => DiffType::Deletion,
-        "modified" => DiffType::Modification,
+        "modified" | "changed" => DiffType::Modification,
         _ => DiffType::Unknown,
     };
 }
+
+// A renamed file is a deletion of the old name and an addition of the new one,
+// the same as a local clone reports it
+pub fn file_changes(files: &[CommitItemFile]) -> Vec<(&str, DiffType)> {
+    return files
+        .iter()
+        .flat_map(|file| {
+            return match (file.status.as_str(), &file.previous_filename) {
+                ("renamed", Some(previous_filename)) => vec![
+                    (previous_filename.as_str(), DiffType::Deletion),
+                    (file.filename.as_str(), DiffType::Addition),
+                ],
+                _ => vec![(file.filename.as_str(), status_to_diff_type(&file.status))],
+            };
+        })
+        .collect();
+}
+
+// Network errors, including a connection dropped while reading the body, are worth retrying.
+// A malformed request or a redirect loop fails the same way again.
+pub fn is_transient(e: &reqwest::Error) -> bool {
+    return !e.is_builder() && !e.is_redirect() && !e.is_status();
+}
+
+// Returns the URL of the next page from a header like
+// `<https://api.github.com/...&page=2>; rel="next", <https://api.github.com/...&page=5>; rel="last"`
+pub fn next_page_url(headers: &HeaderMap) -> Option<String> {
+    let link = headers.get(LINK)?.to_str().ok()?;
+    for entry in link.split(',') {
+        let mut parts = entry.split(';');
+        let url = parts.next()?.trim();
+        let is_next = parts.any(|param| param.trim() == "rel=\"next\"");
+        if is_next && url.starts_with('<') && url.ends_with('>') {
+            return Some(url[1..url.len() - 1].to_string());
+        }
+    }
+    return None;
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::header::HeaderValue;
+
+    use super::*;
+
+    fn link_headers(link: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(LINK, HeaderValue::from_str(link).unwrap());
+        return headers;
+    }
+
+    fn file(filename: &str, status: &str, previous_filename: Option<&str>) -> CommitItemFile {
+        return CommitItemFile {
+            filename: filename.to_string(),
+            status: status.to_string(),
+            previous_filename: previous_filename.map(|name| name.to_string()),
+        };
+    }
+
+    #[test]
+    fn file_changes_splits_a_rename() {
+        let files = vec![
+            file("playlists/1.json", "modified", None),
+            file("playlists/3.json", "renamed", Some("playlists/2.json")),
+        ];
+        let changes = file_changes(&files);
+        assert_eq!(changes.len(), 3);
+        assert!(matches!(
+            changes[0],
+            ("playlists/1.json", DiffType::Modification)
+        ));
+        assert!(matches!(
+            changes[1],
+            ("playlists/2.json", DiffType::Deletion)
+        ));
+        assert!(matches!(
+            changes[2],
+            ("playlists/3.json", DiffType::Addition)
+        ));
+    }
+
+    #[test]
+    fn next_page_url_finds_the_next_link() {
+        let headers = link_headers(
+            "<https://api.github.com/repos/o/r/commits?per_page=100&page=1>; rel=\"prev\", \
+             <https://api.github.com/repos/o/r/commits?per_page=100&page=3>; rel=\"next\", \
+             <https://api.github.com/repos/o/r/commits?per_page=100&page=5>; rel=\"last\"",
+        );
+        assert_eq!(
+            next_page_url(&headers),
+            Some("https://api.github.com/repos/o/r/commits?per_page=100&page=3".to_string())
+        );
+    }
+
+    #[test]
+    fn next_page_url_of_the_last_page_is_none() {
+        let headers = link_headers(
+            "<https://api.github.com/repos/o/r/commits?page=1>; rel=\"first\", \
+             <https://api.github.com/repos/o/r/commits?page=4>; rel=\"prev\"",
+        );
+        assert_eq!(next_page_url(&headers), None);
+        assert_eq!(next_page_url(&HeaderMap::new()), None);
+    }
+}
@@ -1,56 +1,450 @@
+// The codebase prefers explicit returns
+#![allow(clippy::needless_return)]
+
 use dotenv::dotenv;
-use std::env;
+use std::error::Error;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::process;
+
+use checkpoint::{Checkpoint, CheckpointStore};
+use cli::{
+    CacheArgs, CheckpointArgs, Cli, Command, ReportArgs, RowsArgs, SinkArgs, SourceArgs, SyncArgs,
+};
+use commit_source::CommitSource;
+use converter::TableRows;
+use error::AppError;
+use sink::{Sink, Table};
+use spotify_log::defs::LogFilter;
 
 mod bq_client;
+mod checkpoint;
+mod cli;
+mod commit_cache;
+mod commit_source;
+mod config;
+mod config_file;
+mod conversion_report;
 mod converter;
+mod error;
+mod git_client;
 mod github_client;
+mod sink;
 mod spotify_log;
 
+// The checkpoint of the converted rows, saved by the load command once they are written
+const PENDING_CHECKPOINT_FILENAME: &str = "checkpoint.json";
+// Left by convert --full-resync, so that load empties the tables before writing the rows
+const PENDING_FULL_RESYNC_FILENAME: &str = "full_resync";
+
 #[tokio::main]
 async fn main() {
+    // The variables in .env are read as the defaults of the options
     dotenv().ok();
-    let github_token = env::var("GITHUB_TOKEN").unwrap();
-    let repo_owner = env::var("REPO_OWNER").unwrap();
-    let repo_name = env::var("REPO_NAME").unwrap();
-    let gcp_access_token = env::var("GCP_ACCESS_TOKEN").unwrap();
-    let bq_project_id = env::var("BQ_PROJECT_ID").unwrap();
-    let bq_dataset_id = env::var("BQ_DATASET_ID").unwrap();
-    let bq_action_table_id = env::var("BQ_ACTION_TABLE_ID").unwrap();
-    let bq_track_table_id = env::var("BQ_TRACK_TABLE_ID").unwrap();
-    let bq_artist_table_id = env::var("BQ_ARTIST_TABLE_ID").unwrap();
-
-    let github_client = github_client::new(&github_token, &repo_owner, &repo_name);
-    let actions_result = spotify_log::fetch_track_related_actions(&github_client).await;
-    match actions_result {
-        Ok(actions) => {
-            println!("{:?}", actions);
-            let (action_table_rows, track_table_rows, artist_table_rows) =
-                converter::track_related_action_to_table_rows(actions);
-            println!("{:?}", action_table_rows);
-            println!("{:?}", track_table_rows);
-            println!("{:?}", artist_table_rows);
-
-            let bq_client = bq_client::new(&gcp_access_token, &bq_project_id, &bq_dataset_id);
-
-            let actions_insert_result = bq_client
-                .insert_rows(&bq_action_table_id, action_table_rows)
-                .await;
-            if let Err(e) = actions_insert_result {
-                println!("Error inserting actions: {}", e);
-            }
-            let tracks_insert_result = bq_client
-                .insert_rows(&bq_track_table_id, track_table_rows)
-                .await;
-            if let Err(e) = tracks_insert_result {
-                println!("Error inserting tracks: {}", e);
-            }
-            let artists_insert_result = bq_client
-                .insert_rows(&bq_artist_table_id, artist_table_rows)
-                .await;
-            if let Err(e) = artists_insert_result {
-                println!("Error inserting artists: {}", e);
+    let cli = cli::parse();
+    if let Err(e) = run(cli).await {
+        println!("{}", e);
+        process::exit(e.exit_code());
+    }
+}
+
+async fn run(cli: Cli) -> Result<(), AppError> {
+    let command = match cli.command {
+        Some(command) => command,
+        None => Command::Sync(cli.sync),
+    };
+    return match command {
+        Command::Sync(args) => sync(args).await,
+        Command::Fetch { source, cache } => fetch(&source, &cache).await,
+        Command::Convert {
+            cache,
+            rows,
+            sink,
+            checkpoint,
+            report,
+            log,
+            full_resync,
+        } => {
+            let log_filter = config::log_filter(&log);
+            convert(
+                &cache,
+                &rows,
+                &sink,
+                &checkpoint,
+                &report,
+                &log_filter,
+                full_resync,
+            )
+            .await
+        }
+        Command::Load {
+            rows,
+            sink,
+            checkpoint,
+        } => load(&rows, &sink, &checkpoint).await,
+        Command::Inspect { sha, source } => inspect(&sha, &source).await,
+        Command::Schema { json } => {
+            print_schemas(json);
+            Ok(())
+        }
+    };
+}
+
+// Syncs the repository given by the options, or every repository in the config file
+async fn sync(args: SyncArgs) -> Result<(), AppError> {
+    let mut validator = config::Validator::default();
+    let repos = match &args.config_path {
+        Some(config_path) => config_file::repos(&mut validator, config_path, &args),
+        None => vec![config::repo(&mut validator, &args)],
+    };
+    validator.finish()?;
+
+    // A repository that fails does not stop the others, and the run exits with the first error
+    let mut first_error = None;
+    for repo in &repos {
+        if let Some(name) = &repo.name {
+            println!("Syncing {}", name);
+        }
+        if let Err(e) = sync_repo(repo, args.source.fetch_concurrency, args.full_resync).await {
+            if let Some(name) = &repo.name {
+                println!("{}: {}", name, e);
             }
+            first_error.get_or_insert(e);
+        }
+    }
+    return match first_error {
+        Some(e) => Err(e),
+        None => Ok(()),
+    };
+}
+
+// Fetches the commits after the checkpoint, converts them and writes the rows into the sink
+async fn sync_repo(
+    repo: &config::RepoConfig,
+    fetch_concurrency: usize,
+    full_resync: bool,
+) -> Result<(), AppError> {
+    let sink = sink::new(&repo.sink).map_err(AppError::Sink)?;
+    sink.prepare().await.map_err(AppError::Sink)?;
+    let checkpoint_file = checkpoint::file(&repo.checkpoint_path);
+    let checkpoint_store = sink.checkpoint_store().unwrap_or(&checkpoint_file);
+    let checkpoint = load_checkpoint(checkpoint_store, full_resync)?;
+
+    let commit_source = commit_source::new(&repo.source)?;
+    let (actions, next_checkpoint) = spotify_log::fetch_actions(
+        &commit_source,
+        &repo.log_filter,
+        checkpoint.as_ref(),
+        fetch_concurrency,
+    )
+    .await
+    .map_err(|e| commit_source.error(e))?;
+    let (table_rows, conversion_error) =
+        actions_to_table_rows(actions, &repo.conversion_report_path)?;
+
+    // The whole history is written again, so the rows of the earlier runs are removed first
+    if full_resync {
+        sink.truncate().await.map_err(AppError::Sink)?;
+    }
+    // Keep the previous checkpoint so that the next run retries the failed commits
+    if !write_table_rows(&sink, table_rows).await {
+        return Err(AppError::Sink(From::from(if full_resync {
+            "Some rows could not be written; run with --full-resync again, since the tables have been emptied"
+        } else {
+            "Some rows could not be written"
+        })));
+    }
+    if let Some(next_checkpoint) = next_checkpoint {
+        checkpoint_store
+            .save(&next_checkpoint)
+            .map_err(AppError::LocalState)?;
+    }
+
+    // Everything else has been synced, so the skipped commits are reported with their own exit code
+    return match conversion_error {
+        Some(e) => Err(e),
+        None => Ok(()),
+    };
+}
+
+// Dumps the commits that are not cached yet
+async fn fetch(source_args: &SourceArgs, cache_args: &CacheArgs) -> Result<(), AppError> {
+    let mut validator = config::Validator::default();
+    let repo_source = config::repo_source(&mut validator, source_args);
+    validator.finish()?;
+
+    let commit_source = commit_source::new(&repo_source)?;
+    let commit_cache = commit_cache::new(&cache_args.cache_dir);
+    return commit_cache
+        .fill(&commit_source, source_args.fetch_concurrency)
+        .await
+        .map_err(|e| commit_source.error(e));
+}
+
+// Converts the cached commits after the checkpoint into NDJSON files of the rows,
+// which replace the files of the previous conversion
+async fn convert(
+    cache_args: &CacheArgs,
+    rows_args: &RowsArgs,
+    sink_args: &SinkArgs,
+    checkpoint_args: &CheckpointArgs,
+    report_args: &ReportArgs,
+    log_filter: &LogFilter,
+    full_resync: bool,
+) -> Result<(), AppError> {
+    let mut validator = config::Validator::default();
+    let sink_config = config::sink(&mut validator, sink_args);
+    validator.finish()?;
+
+    // The checkpoint is read from where load saves it, which is the sink itself for SQLite
+    let sink = sink::new(&sink_config).map_err(AppError::Sink)?;
+    let checkpoint_file = checkpoint::file(&checkpoint_args.checkpoint_path);
+    let checkpoint_store = match sink.checkpoint_store() {
+        Some(checkpoint_store) => {
+            sink.prepare().await.map_err(AppError::Sink)?;
+            checkpoint_store
         }
-        Err(e) => println!("{:?}", e),
+        None => &checkpoint_file,
+    };
+    let checkpoint = load_checkpoint(checkpoint_store, full_resync)?;
+    let commit_cache = commit_cache::new(&cache_args.cache_dir);
+    // The cache is read from the local disk, so there is nothing to gain from concurrency
+    let (actions, next_checkpoint) =
+        spotify_log::fetch_actions(&commit_cache, log_filter, checkpoint.as_ref(), 1)
+            .await
+            .map_err(AppError::LocalState)?;
+    let (table_rows, conversion_error) =
+        actions_to_table_rows(actions, &report_args.conversion_report_path)?;
+
+    let rows_sink = sink::ndjson_file::new(&rows_args.rows_dir);
+    rows_sink.prepare().await.map_err(AppError::LocalState)?;
+    rows_sink.clear().map_err(AppError::LocalState)?;
+    if !write_table_rows(&rows_sink, table_rows).await {
+        return Err(AppError::LocalState(From::from(
+            "Some rows could not be written",
+        )));
+    }
+    let pending_checkpoint_file = pending_checkpoint_file(rows_args);
+    match next_checkpoint {
+        Some(next_checkpoint) => pending_checkpoint_file.save(&next_checkpoint),
+        None => pending_checkpoint_file.remove(),
     }
+    .map_err(AppError::LocalState)?;
+    let pending_full_resync_path = pending_full_resync_path(rows_args);
+    if full_resync {
+        fs::write(&pending_full_resync_path, "")
+    } else {
+        remove_file_if_exists(&pending_full_resync_path)
+    }
+    .map_err(|e| AppError::LocalState(Box::new(e)))?;
+
+    return match conversion_error {
+        Some(e) => Err(e),
+        None => Ok(()),
+    };
+}
+
+// Writes the rows of the last conversion into the sink, then moves the checkpoint past them
+// and removes the row files, so that a second load does not write them again
+async fn load(
+    rows_args: &RowsArgs,
+    sink_args: &SinkArgs,
+    checkpoint_args: &CheckpointArgs,
+) -> Result<(), AppError> {
+    let mut validator = config::Validator::default();
+    let sink_config = config::sink(&mut validator, sink_args);
+    validator.finish()?;
+
+    let rows_sink = sink::ndjson_file::new(&rows_args.rows_dir);
+    let table_rows = rows_sink.read_table_rows().map_err(AppError::LocalState)?;
+    let next_checkpoint = pending_checkpoint_file(rows_args)
+        .load()
+        .map_err(AppError::LocalState)?;
+    let pending_full_resync_path = pending_full_resync_path(rows_args);
+
+    let sink = sink::new(&sink_config).map_err(AppError::Sink)?;
+    sink.prepare().await.map_err(AppError::Sink)?;
+    // Kept until the rows are written, so that a failed load empties the tables again when rerun
+    if pending_full_resync_path.exists() {
+        sink.truncate().await.map_err(AppError::Sink)?;
+    }
+    if !write_table_rows(&sink, table_rows).await {
+        return Err(AppError::Sink(From::from("Some rows could not be written")));
+    }
+    if let Some(next_checkpoint) = next_checkpoint {
+        let checkpoint_file = checkpoint::file(&checkpoint_args.checkpoint_path);
+        sink.checkpoint_store()
+            .unwrap_or(&checkpoint_file)
+            .save(&next_checkpoint)
+            .map_err(AppError::LocalState)?;
+    }
+    rows_sink.clear().map_err(AppError::LocalState)?;
+    pending_checkpoint_file(rows_args)
+        .remove()
+        .map_err(AppError::LocalState)?;
+    remove_file_if_exists(&pending_full_resync_path)
+        .map_err(|e| AppError::LocalState(Box::new(e)))?;
+    return Ok(());
+}
+
+async fn inspect(sha: &str, source_args: &SourceArgs) -> Result<(), AppError> {
+    let mut validator = config::Validator::default();
+    let repo_source = config::repo_source(&mut validator, source_args);
+    validator.finish()?;
+
+    let commit_source = commit_source::new(&repo_source)?;
+    let commit = commit_source
+        .fetch_commit_by_sha(sha)
+        .await
+        .map_err(|e| commit_source.error(e))?;
+    spotify_log::inspect_commit(&commit, &config::log_filter(&source_args.log));
+    return Ok(());
+}
+
+// Prints the columns of every table, or their BigQuery JSON schemas
+fn print_schemas(json: bool) {
+    if json {
+        let mut schemas = serde_json::Map::new();
+        for table in Table::ALL {
+            let fields = bq_client::schema::columns_to_fields(&table.columns());
+            schemas.insert(
+                table.name().to_string(),
+                serde_json::to_value(fields).unwrap_or_default(),
+            );
+        }
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&schemas).unwrap_or_default()
+        );
+        return;
+    }
+    for table in Table::ALL {
+        println!("{}", table.name());
+        for field in bq_client::schema::columns_to_fields(&table.columns()) {
+            println!(
+                "  {:<24} {:<10} {}",
+                field.name, field.field_type, field.mode
+            );
+        }
+    }
+}
+
+// Returns None when the sync starts from the beginning of the history
+fn load_checkpoint(
+    checkpoint_store: &dyn CheckpointStore,
+    full_resync: bool,
+) -> Result<Option<Checkpoint>, AppError> {
+    if full_resync {
+        return Ok(None);
+    }
+    let checkpoint = checkpoint_store.load().map_err(AppError::LocalState)?;
+    if let Some(checkpoint) = &checkpoint {
+        println!(
+            "Resuming after commit {} ({})",
+            checkpoint.sha, checkpoint.datetime
+        );
+    }
+    return Ok(checkpoint);
+}
+
+fn pending_checkpoint_file(rows_args: &RowsArgs) -> checkpoint::CheckpointFile {
+    let path = Path::new(&rows_args.rows_dir).join(PENDING_CHECKPOINT_FILENAME);
+    return checkpoint::file(&path.to_string_lossy());
+}
+
+fn pending_full_resync_path(rows_args: &RowsArgs) -> PathBuf {
+    return Path::new(&rows_args.rows_dir).join(PENDING_FULL_RESYNC_FILENAME);
+}
+
+fn remove_file_if_exists(path: &Path) -> io::Result<()> {
+    return match fs::remove_file(path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    };
+}
+
+// Reports the commits that were skipped or disagree with their messages, and converts the actions into rows.
+// Also returns the error to exit with once everything else has been written.
+fn actions_to_table_rows(
+    actions: spotify_log::defs::Actions,
+    conversion_report_path: &str,
+) -> Result<(TableRows, Option<AppError>), AppError> {
+    let conversion_error = AppError::from_conversion_errors(&actions.conversion_errors);
+    for mismatched_commit in &actions.mismatched_commits {
+        println!(
+            "Commit {} says {:?} but its diff has {:?}: {}",
+            mismatched_commit.commit_sha,
+            mismatched_commit.message_action_type,
+            mismatched_commit.diff_action_types,
+            mismatched_commit.message,
+        );
+    }
+    let table_rows = converter::actions_to_table_rows(actions);
+    for row in &table_rows.conversion_errors {
+        println!("Skipped commit {}: {}", row.commit_sha, row.reason);
+    }
+
+    // Record the commits that could not be converted, so that the gaps can be audited.
+    // The checkpoint is not saved if the report cannot be written, so that the commits are not lost
+    if !table_rows.conversion_errors.is_empty() {
+        let added_row_count =
+            conversion_report::append(conversion_report_path, &table_rows.conversion_errors)
+                .map_err(AppError::LocalState)?;
+        println!(
+            "Added {} commits to {}",
+            added_row_count, conversion_report_path
+        );
+    }
+    return Ok((table_rows, conversion_error));
+}
+
+// Writes the rows of every table and returns whether all of them were written
+async fn write_table_rows(sink: &impl Sink, table_rows: TableRows) -> bool {
+    return [
+        report_write_result(
+            Table::Action,
+            sink.write_rows(Table::Action, table_rows.actions).await,
+        ),
+        report_write_result(
+            Table::Track,
+            sink.write_rows(Table::Track, table_rows.tracks).await,
+        ),
+        report_write_result(
+            Table::Artist,
+            sink.write_rows(Table::Artist, table_rows.artists).await,
+        ),
+        report_write_result(
+            Table::TrackHistory,
+            sink.write_rows(Table::TrackHistory, table_rows.track_history)
+                .await,
+        ),
+        report_write_result(
+            Table::PlaylistAction,
+            sink.write_rows(Table::PlaylistAction, table_rows.playlist_actions)
+                .await,
+        ),
+        report_write_result(
+            Table::Playlist,
+            sink.write_rows(Table::Playlist, table_rows.playlists).await,
+        ),
+        report_write_result(
+            Table::ConversionError,
+            sink.write_rows(Table::ConversionError, table_rows.conversion_errors)
+                .await,
+        ),
+    ]
+    .iter()
+    .all(|is_written| *is_written);
+}
+
+// Prints the error of writing a table and returns whether it was written
+fn report_write_result(table: Table, result: Result<(), Box<dyn Error>>) -> bool {
+    return match result {
+        Ok(()) => true,
+        Err(e) => {
+            println!("Error writing {}: {}", table.name(), e);
+            false
+        }
+    };
 }
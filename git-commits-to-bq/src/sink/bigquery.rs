@@ -0,0 +1,272 @@
+use serde::Serialize;
+use std::error::Error;
+
+use super::{Sink, Table};
+use crate::bq_client::{self, BqClient, WriteMode};
+use crate::config::{BigQueryConfig, TableIds};
+use crate::converter::{self, RowKey, TableSchema};
+
+// Writes the rows into the BigQuery tables; the optional tables are skipped unless their IDs are given
+pub struct BigQuerySink {
+    bq_client: BqClient,
+    table_ids: TableIds,
+    write_mode: WriteMode,
+}
+
+impl BigQuerySink {
+    fn table_id(&self, table: Table) -> Option<&str> {
+        let table_ids = &self.table_ids;
+        return match table {
+            Table::Action => Some(&table_ids.action),
+            Table::Track => Some(&table_ids.track),
+            Table::Artist => Some(&table_ids.artist),
+            Table::TrackHistory => table_ids.track_history.as_deref(),
+            Table::PlaylistAction => table_ids.playlist_action.as_deref(),
+            Table::Playlist => table_ids.playlist.as_deref(),
+            Table::ConversionError => table_ids.conversion_error.as_deref(),
+        };
+    }
+
+    // Creates the table unless it exists, and brings the schema of the existing table up to date otherwise.
+    // Fails with every drift that would make the writes fail, and reports the rest.
+    async fn ensure_table<T: TableSchema>(&self, table: Table) -> Result<(), Box<dyn Error>> {
+        let table_id = match self.table_id(table) {
+            Some(table_id) => table_id,
+            None => return Ok(()),
+        };
+        match self.bq_client.ensure_table::<T>(table_id).await? {
+            bq_client::TableStatus::Created => println!("Created table {}", table_id),
+            bq_client::TableStatus::Exists {
+                added_columns,
+                drifts,
+            } => {
+                for column in added_columns {
+                    println!("Added column {} to table {}", column, table_id);
+                }
+                let (breaking_drifts, drifts): (Vec<_>, Vec<_>) =
+                    drifts.into_iter().partition(|drift| drift.breaks_writes());
+                for drift in drifts {
+                    println!("Schema drift in table {}: {}", table_id, drift);
+                }
+                if !breaking_drifts.is_empty() {
+                    let descriptions: Vec<String> = breaking_drifts
+                        .iter()
+                        .map(|drift| format!("\n  {}", drift))
+                        .collect();
+                    return Err(From::from(format!(
+                        "The schema of table {} has to be fixed by hand before the rows can be written:{}",
+                        table_id,
+                        descriptions.concat()
+                    )));
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    // Writes the rows into a table and returns whether every row was written
+    async fn write_table<T>(
+        &self,
+        table: Table,
+        table_id: &str,
+        rows: Vec<T>,
+        merge_key_column: Option<&str>, // None for a table that is only appended to
+    ) -> bool
+    where
+        T: Serialize + RowKey + TableSchema,
+    {
+        let table_name = table.name();
+        return match (self.write_mode, merge_key_column) {
+            (WriteMode::Streaming, _) => {
+                report_insert_result(table_name, self.bq_client.insert_rows(table_id, rows).await)
+            }
+            (WriteMode::Merge, Some(key_column)) => report_job_result(
+                table_name,
+                self.bq_client.merge_rows(table_id, rows, key_column).await,
+            ),
+            (WriteMode::LoadJob, _) | (WriteMode::Merge, None) => {
+                report_job_result(table_name, self.bq_client.load_rows(table_id, rows).await)
+            }
+        };
+    }
+
+    // Appends the new track versions, removes those that repeat the version before them,
+    // then closes the versions they supersede.
+    // Versions written by streaming inserts cannot be changed until they leave the streaming buffer,
+    // so the ones from earlier runs are compared and closed only in the load and merge modes.
+    async fn write_track_history<T>(&self, table_id: &str, rows: Vec<T>) -> bool
+    where
+        T: Serialize + RowKey + TableSchema,
+    {
+        let has_new_versions = !rows.is_empty();
+        if !self
+            .write_table(Table::TrackHistory, table_id, rows, None)
+            .await
+        {
+            return false;
+        }
+        if !has_new_versions || matches!(self.write_mode, WriteMode::Streaming) {
+            return true;
+        }
+        if !report_job_result(
+            "track_history unchanged versions",
+            self.bq_client
+                .remove_unchanged_versions(table_id, "id", &["name", "artist_ids"])
+                .await,
+        ) {
+            return false;
+        }
+        return report_job_result(
+            "track_history valid_to",
+            self.bq_client
+                .close_superseded_versions(table_id, "id")
+                .await,
+        );
+    }
+}
+
+impl Sink for BigQuerySink {
+    // Creates the dataset and the tables on the first run, and reports schema drift afterwards
+    async fn prepare(&self) -> Result<(), Box<dyn Error>> {
+        if self.bq_client.ensure_dataset().await? {
+            println!("Created the dataset");
+        }
+        self.ensure_table::<converter::ActionTableRow>(Table::Action)
+            .await?;
+        self.ensure_table::<converter::TrackTableRow>(Table::Track)
+            .await?;
+        self.ensure_table::<converter::ArtistTableRow>(Table::Artist)
+            .await?;
+        self.ensure_table::<converter::TrackHistoryTableRow>(Table::TrackHistory)
+            .await?;
+        self.ensure_table::<converter::PlaylistActionTableRow>(Table::PlaylistAction)
+            .await?;
+        self.ensure_table::<converter::PlaylistTableRow>(Table::Playlist)
+            .await?;
+        self.ensure_table::<converter::ConversionErrorTableRow>(Table::ConversionError)
+            .await?;
+        return Ok(());
+    }
+
+    // Rows still in the streaming buffer cannot be removed, so this fails for a while after streaming inserts
+    async fn truncate(&self) -> Result<(), Box<dyn Error>> {
+        for table in Table::ALL {
+            let table_id = match self.table_id(table) {
+                Some(table_id) => table_id,
+                None => continue,
+            };
+            let report = self.bq_client.truncate_table(table_id).await?;
+            if !report.is_success() {
+                return Err(From::from(format!(
+                    "Emptying table {} failed (job {}): {}",
+                    table_id,
+                    report.job_id,
+                    report.errors.join(", ")
+                )));
+            }
+            println!("Emptied table {}", table_id);
+        }
+        return Ok(());
+    }
+
+    async fn write_rows<T>(&self, table: Table, rows: Vec<T>) -> Result<(), Box<dyn Error>>
+    where
+        T: Serialize + RowKey + TableSchema,
+    {
+        let table_id = match self.table_id(table) {
+            Some(table_id) => table_id,
+            None => return Ok(()),
+        };
+        // The dimension tables are merged on the ID in the merge mode
+        let is_written = match table {
+            Table::TrackHistory => self.write_track_history(table_id, rows).await,
+            Table::Track | Table::Artist | Table::Playlist => {
+                self.write_table(table, table_id, rows, Some("id")).await
+            }
+            Table::Action | Table::PlaylistAction | Table::ConversionError => {
+                self.write_table(table, table_id, rows, None).await
+            }
+        };
+        if !is_written {
+            return Err(From::from(format!(
+                "Some rows could not be written into {}",
+                table_id
+            )));
+        }
+        return Ok(());
+    }
+}
+
+// Prints the outcome of a load or merge job and returns whether it succeeded
+fn report_job_result(
+    table_name: &str,
+    result: Result<bq_client::JobReport, Box<dyn Error>>,
+) -> bool {
+    let report = match result {
+        Ok(report) => report,
+        Err(e) => {
+            println!("Error writing {}: {}", table_name, e);
+            return false;
+        }
+    };
+    if report.is_success() {
+        println!(
+            "Wrote {} rows into {} (job {})",
+            report.affected_row_count, table_name, report.job_id
+        );
+    }
+    for error in &report.errors {
+        println!(
+            "Error writing {} (job {}): {}",
+            table_name, report.job_id, error
+        );
+    }
+    return report.is_success();
+}
+
+// Prints the outcome of inserting rows into a table and returns whether every row was inserted
+fn report_insert_result(
+    table_name: &str,
+    result: Result<bq_client::InsertRowsReport, Box<dyn Error>>,
+) -> bool {
+    let report = match result {
+        Ok(report) => report,
+        Err(e) => {
+            println!("Error inserting {}: {}", table_name, e);
+            return false;
+        }
+    };
+    println!(
+        "Inserted {} rows into {}",
+        report.inserted_row_count, table_name
+    );
+    for failed_row in &report.failed_rows {
+        println!(
+            "Error inserting {} row {}: {}",
+            table_name,
+            failed_row.index,
+            failed_row.reasons.join(", ")
+        );
+    }
+    for failed_batch in &report.failed_batches {
+        println!(
+            "Error inserting {} rows {}..{}: {}",
+            table_name, failed_batch.rows.start, failed_batch.rows.end, failed_batch.reason
+        );
+    }
+    return report.is_success();
+}
+
+pub fn new(config: &BigQueryConfig) -> BigQuerySink {
+    return BigQuerySink {
+        bq_client: bq_client::new(
+            config.credentials.clone(),
+            &config.project_id,
+            &config.dataset_id,
+            config.dataset_location.clone(),
+            config.insert_limits,
+        ),
+        table_ids: config.table_ids.clone(),
+        write_mode: config.write_mode,
+    };
+}
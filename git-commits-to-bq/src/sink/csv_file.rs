@@ -0,0 +1,78 @@
+use serde::Serialize;
+use std::error::Error;
+use std::fs::{self, OpenOptions};
+use std::path::PathBuf;
+
+use super::{util, Sink, Table};
+use crate::converter::{RowKey, TableSchema};
+
+// Appends the rows of each table to <dir>/<table>.csv, which starts with a header row
+pub struct CsvFileSink {
+    dir: PathBuf,
+}
+
+impl CsvFileSink {
+    fn path(&self, table: Table) -> PathBuf {
+        return self.dir.join(format!("{}.csv", table.name()));
+    }
+}
+
+impl Sink for CsvFileSink {
+    async fn prepare(&self) -> Result<(), Box<dyn Error>> {
+        fs::create_dir_all(&self.dir)?;
+        return Ok(());
+    }
+
+    async fn truncate(&self) -> Result<(), Box<dyn Error>> {
+        for table in Table::ALL {
+            util::remove_path(&self.path(table))?;
+        }
+        return Ok(());
+    }
+
+    async fn write_rows<T>(&self, table: Table, rows: Vec<T>) -> Result<(), Box<dyn Error>>
+    where
+        T: Serialize + RowKey + TableSchema,
+    {
+        if rows.is_empty() {
+            return Ok(());
+        }
+        let path = self.path(table);
+        let columns = T::columns();
+        let column_names: Vec<&str> = columns.iter().map(|column| column.name).collect();
+
+        // Appending rows under a header with other columns would misalign them
+        let is_new = fs::metadata(&path)
+            .map(|metadata| metadata.len() == 0)
+            .unwrap_or(true);
+        if !is_new {
+            let mut reader = csv::Reader::from_path(&path)?;
+            if reader.headers()?.iter().ne(column_names.iter().copied()) {
+                return Err(From::from(format!(
+                    "{} has other columns than {}",
+                    path.display(),
+                    column_names.join(",")
+                )));
+            }
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let mut writer = csv::Writer::from_writer(file);
+        if is_new {
+            writer.write_record(&column_names)?;
+        }
+        for row in &rows {
+            let values = util::row_to_values(row, &columns)?;
+            writer.write_record(values.iter().map(util::value_to_text))?;
+        }
+        writer.flush()?;
+        println!("Wrote {} rows into {}", rows.len(), path.display());
+        return Ok(());
+    }
+}
+
+pub fn new(dir: &str) -> CsvFileSink {
+    return CsvFileSink {
+        dir: PathBuf::from(dir),
+    };
+}
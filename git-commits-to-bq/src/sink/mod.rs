@@ -0,0 +1,148 @@
+use serde::Serialize;
+use std::error::Error;
+
+use crate::checkpoint::CheckpointStore;
+use crate::config::SinkConfig;
+use crate::converter::{self, Column, RowKey, TableSchema};
+
+pub mod bigquery;
+pub mod csv_file;
+pub mod ndjson_file;
+pub mod parquet_file;
+pub mod sqlite;
+mod util;
+
+// The tables the converted rows are written into
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Table {
+    Action,
+    Track,
+    Artist,
+    TrackHistory,
+    PlaylistAction,
+    Playlist,
+    ConversionError,
+}
+
+impl Table {
+    pub const ALL: [Table; 7] = [
+        Table::Action,
+        Table::Track,
+        Table::Artist,
+        Table::TrackHistory,
+        Table::PlaylistAction,
+        Table::Playlist,
+        Table::ConversionError,
+    ];
+
+    // Also the name of the file or the table in the local sinks
+    pub fn name(self) -> &'static str {
+        return match self {
+            Table::Action => "actions",
+            Table::Track => "tracks",
+            Table::Artist => "artists",
+            Table::TrackHistory => "track_history",
+            Table::PlaylistAction => "playlist_actions",
+            Table::Playlist => "playlists",
+            Table::ConversionError => "conversion_errors",
+        };
+    }
+
+    pub fn columns(self) -> Vec<Column> {
+        return match self {
+            Table::Action => converter::ActionTableRow::columns(),
+            Table::Track => converter::TrackTableRow::columns(),
+            Table::Artist => converter::ArtistTableRow::columns(),
+            Table::TrackHistory => converter::TrackHistoryTableRow::columns(),
+            Table::PlaylistAction => converter::PlaylistActionTableRow::columns(),
+            Table::Playlist => converter::PlaylistTableRow::columns(),
+            Table::ConversionError => converter::ConversionErrorTableRow::columns(),
+        };
+    }
+}
+
+// Where the converted rows are written
+pub trait Sink {
+    // Creates whatever the tables are stored in on the first run
+    async fn prepare(&self) -> Result<(), Box<dyn Error>>;
+
+    // Removes the rows of every table, before a full resync writes the whole history again
+    async fn truncate(&self) -> Result<(), Box<dyn Error>>;
+
+    // Fails when any of the rows could not be written
+    async fn write_rows<T>(&self, table: Table, rows: Vec<T>) -> Result<(), Box<dyn Error>>
+    where
+        T: Serialize + RowKey + TableSchema;
+
+    // A sink that keeps the checkpoint along with the rows; the checkpoint file is used otherwise
+    fn checkpoint_store(&self) -> Option<&dyn CheckpointStore> {
+        return None;
+    }
+}
+
+// The sink chosen at runtime
+pub enum AnySink {
+    BigQuery(Box<bigquery::BigQuerySink>),
+    Ndjson(ndjson_file::NdjsonFileSink),
+    Csv(csv_file::CsvFileSink),
+    Parquet(parquet_file::ParquetFileSink),
+    Sqlite(sqlite::SqliteSink),
+}
+
+impl Sink for AnySink {
+    async fn prepare(&self) -> Result<(), Box<dyn Error>> {
+        return match self {
+            AnySink::BigQuery(sink) => sink.prepare().await,
+            AnySink::Ndjson(sink) => sink.prepare().await,
+            AnySink::Csv(sink) => sink.prepare().await,
+            AnySink::Parquet(sink) => sink.prepare().await,
+            AnySink::Sqlite(sink) => sink.prepare().await,
+        };
+    }
+
+    async fn truncate(&self) -> Result<(), Box<dyn Error>> {
+        return match self {
+            AnySink::BigQuery(sink) => sink.truncate().await,
+            AnySink::Ndjson(sink) => sink.truncate().await,
+            AnySink::Csv(sink) => sink.truncate().await,
+            AnySink::Parquet(sink) => sink.truncate().await,
+            AnySink::Sqlite(sink) => sink.truncate().await,
+        };
+    }
+
+    async fn write_rows<T>(&self, table: Table, rows: Vec<T>) -> Result<(), Box<dyn Error>>
+    where
+        T: Serialize + RowKey + TableSchema,
+    {
+        return match self {
+            AnySink::BigQuery(sink) => sink.write_rows(table, rows).await,
+            AnySink::Ndjson(sink) => sink.write_rows(table, rows).await,
+            AnySink::Csv(sink) => sink.write_rows(table, rows).await,
+            AnySink::Parquet(sink) => sink.write_rows(table, rows).await,
+            AnySink::Sqlite(sink) => sink.write_rows(table, rows).await,
+        };
+    }
+
+    fn checkpoint_store(&self) -> Option<&dyn CheckpointStore> {
+        return match self {
+            AnySink::BigQuery(sink) => sink.checkpoint_store(),
+            AnySink::Ndjson(sink) => sink.checkpoint_store(),
+            AnySink::Csv(sink) => sink.checkpoint_store(),
+            AnySink::Parquet(sink) => sink.checkpoint_store(),
+            AnySink::Sqlite(sink) => sink.checkpoint_store(),
+        };
+    }
+}
+
+pub fn new(sink_config: &SinkConfig) -> Result<AnySink, Box<dyn Error>> {
+    return Ok(match sink_config {
+        SinkConfig::BigQuery(bq_config) => AnySink::BigQuery(Box::new(bigquery::new(bq_config))),
+        SinkConfig::Ndjson { dir } => AnySink::Ndjson(ndjson_file::new(dir)),
+        SinkConfig::Csv { dir } => AnySink::Csv(csv_file::new(dir)),
+        SinkConfig::Parquet {
+            dir,
+            partition_actions_by_month,
+        } => AnySink::Parquet(parquet_file::new(dir, *partition_actions_by_month)),
+        SinkConfig::Sqlite { path } => AnySink::Sqlite(sqlite::new(path)?),
+    });
+}
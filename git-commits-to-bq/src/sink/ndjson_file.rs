@@ -0,0 +1,94 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::error::Error;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, ErrorKind, Write};
+use std::path::PathBuf;
+
+use super::{Sink, Table};
+use crate::converter::{RowKey, TableRows, TableSchema};
+
+// Appends the rows of each table to <dir>/<table>.ndjson, one JSON object per line
+pub struct NdjsonFileSink {
+    dir: PathBuf,
+}
+
+impl Sink for NdjsonFileSink {
+    async fn prepare(&self) -> Result<(), Box<dyn Error>> {
+        fs::create_dir_all(&self.dir)?;
+        return Ok(());
+    }
+
+    async fn truncate(&self) -> Result<(), Box<dyn Error>> {
+        return self.clear();
+    }
+
+    async fn write_rows<T>(&self, table: Table, rows: Vec<T>) -> Result<(), Box<dyn Error>>
+    where
+        T: Serialize + RowKey + TableSchema,
+    {
+        if rows.is_empty() {
+            return Ok(());
+        }
+        let path = self.path(table);
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let mut writer = BufWriter::new(file);
+        for row in &rows {
+            serde_json::to_writer(&mut writer, row)?;
+            writer.write_all(b"\n")?;
+        }
+        writer.flush()?;
+        println!("Wrote {} rows into {}", rows.len(), path.display());
+        return Ok(());
+    }
+}
+
+impl NdjsonFileSink {
+    fn path(&self, table: Table) -> PathBuf {
+        return self.dir.join(format!("{}.ndjson", table.name()));
+    }
+
+    // Reads back the rows of a table; a table without a file has no rows
+    fn read_rows<T: DeserializeOwned>(&self, table: Table) -> Result<Vec<T>, Box<dyn Error>> {
+        let file = match File::open(self.path(table)) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(Box::new(e)),
+        };
+        let mut rows = vec![];
+        for line in BufReader::new(file).lines() {
+            rows.push(serde_json::from_str(&line?)?);
+        }
+        return Ok(rows);
+    }
+
+    pub fn read_table_rows(&self) -> Result<TableRows, Box<dyn Error>> {
+        return Ok(TableRows {
+            actions: self.read_rows(Table::Action)?,
+            tracks: self.read_rows(Table::Track)?,
+            artists: self.read_rows(Table::Artist)?,
+            track_history: self.read_rows(Table::TrackHistory)?,
+            playlist_actions: self.read_rows(Table::PlaylistAction)?,
+            playlists: self.read_rows(Table::Playlist)?,
+            conversion_errors: self.read_rows(Table::ConversionError)?,
+        });
+    }
+
+    // Removes the files of every table, so that the next rows are not appended to old ones
+    pub fn clear(&self) -> Result<(), Box<dyn Error>> {
+        for table in Table::ALL {
+            match fs::remove_file(self.path(table)) {
+                Ok(()) => {}
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(Box::new(e)),
+            }
+        }
+        return Ok(());
+    }
+}
+
+pub fn new(dir: &str) -> NdjsonFileSink {
+    return NdjsonFileSink {
+        dir: PathBuf::from(dir),
+    };
+}
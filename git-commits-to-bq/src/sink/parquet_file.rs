@@ -0,0 +1,183 @@
+use arrow_array::builder::{ListBuilder, StringBuilder};
+use arrow_array::{ArrayRef, Int64Array, RecordBatch, StringArray, TimestampMicrosecondArray};
+use arrow_schema::{DataType, Field, Schema, TimeUnit};
+use chrono::prelude::*;
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs::{self, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use super::{util, Sink, Table};
+use crate::converter::{Column, ColumnMode, ColumnType, RowKey, TableSchema};
+
+// Writes the rows of each table into a new Parquet file per run, <dir>/<table>/part-<run>.parquet.
+// The actions can be partitioned by the month of their timestamp into <dir>/actions/month=YYYY-MM/,
+// which BigQuery, DuckDB and pandas read as a hive partition.
+pub struct ParquetFileSink {
+    dir: PathBuf,
+    partition_actions_by_month: bool,
+    run_id: String, // Keeps the files of earlier runs, e.g. 20240101T000000.123456789Z
+}
+
+impl ParquetFileSink {
+    fn part_path(&self, table_dir: &Path) -> PathBuf {
+        return table_dir.join(format!("part-{}.parquet", self.run_id));
+    }
+}
+
+impl Sink for ParquetFileSink {
+    async fn prepare(&self) -> Result<(), Box<dyn Error>> {
+        fs::create_dir_all(&self.dir)?;
+        return Ok(());
+    }
+
+    // Removes the files of the earlier runs along with the month partitions
+    async fn truncate(&self) -> Result<(), Box<dyn Error>> {
+        for table in Table::ALL {
+            util::remove_path(&self.dir.join(table.name()))?;
+        }
+        return Ok(());
+    }
+
+    async fn write_rows<T>(&self, table: Table, rows: Vec<T>) -> Result<(), Box<dyn Error>>
+    where
+        T: Serialize + RowKey + TableSchema,
+    {
+        if rows.is_empty() {
+            return Ok(());
+        }
+        let columns = T::columns();
+        let mut value_rows = vec![];
+        for row in &rows {
+            value_rows.push(util::row_to_values(row, &columns)?);
+        }
+
+        let table_dir = self.dir.join(table.name());
+        if table != Table::Action || !self.partition_actions_by_month {
+            write_file(&self.part_path(&table_dir), &columns, &value_rows)?;
+            return Ok(());
+        }
+        let timestamp_index = columns
+            .iter()
+            .position(|column| column.name == "timestamp")
+            .ok_or("The actions have no timestamp column")?;
+        let mut month_to_rows: BTreeMap<String, Vec<Vec<Value>>> = BTreeMap::new();
+        for values in value_rows {
+            let month = parse_timestamp(&values[timestamp_index])?
+                .format("%Y-%m")
+                .to_string();
+            month_to_rows.entry(month).or_default().push(values);
+        }
+        for (month, value_rows) in &month_to_rows {
+            let partition_dir = table_dir.join(format!("month={}", month));
+            write_file(&self.part_path(&partition_dir), &columns, value_rows)?;
+        }
+        return Ok(());
+    }
+}
+
+fn write_file(path: &Path, columns: &[Column], rows: &[Vec<Value>]) -> Result<(), Box<dyn Error>> {
+    let schema = Arc::new(Schema::new(
+        columns.iter().map(column_to_field).collect::<Vec<Field>>(),
+    ));
+    let mut arrays = vec![];
+    for (index, column) in columns.iter().enumerate() {
+        arrays.push(column_to_array(
+            column,
+            rows.iter().map(|values| &values[index]),
+        )?);
+    }
+    let batch = RecordBatch::try_new(schema.clone(), arrays)?;
+
+    if let Some(parent_dir) = path.parent() {
+        fs::create_dir_all(parent_dir)?;
+    }
+    let properties = WriterProperties::builder()
+        .set_compression(Compression::SNAPPY)
+        .build();
+    // A file of another run is never overwritten, even if the run IDs collide
+    let file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .map_err(|e| format!("{} cannot be created: {}", path.display(), e))?;
+    let mut writer = ArrowWriter::try_new(file, schema, Some(properties))?;
+    writer.write(&batch)?;
+    writer.close()?;
+    println!("Wrote {} rows into {}", rows.len(), path.display());
+    return Ok(());
+}
+
+// Timestamps are stored with the TIMESTAMP logical type in microseconds, adjusted to UTC,
+// and repeated columns as lists
+fn column_to_field(column: &Column) -> Field {
+    let data_type = match (column.column_type, column.mode) {
+        (ColumnType::String, ColumnMode::Repeated) => {
+            DataType::List(Arc::new(Field::new("item", DataType::Utf8, false)))
+        }
+        (ColumnType::String, _) => DataType::Utf8,
+        (ColumnType::Integer, _) => DataType::Int64,
+        (ColumnType::Timestamp, _) => {
+            DataType::Timestamp(TimeUnit::Microsecond, Some("UTC".into()))
+        }
+    };
+    return Field::new(column.name, data_type, column.mode == ColumnMode::Nullable);
+}
+
+fn column_to_array<'a>(
+    column: &Column,
+    values: impl Iterator<Item = &'a Value>,
+) -> Result<ArrayRef, Box<dyn Error>> {
+    return match (column.column_type, column.mode) {
+        (ColumnType::String, ColumnMode::Repeated) => {
+            let mut builder = ListBuilder::new(StringBuilder::new())
+                .with_field(Arc::new(Field::new("item", DataType::Utf8, false)));
+            for value in values {
+                if let Value::Array(elements) = value {
+                    for element in elements {
+                        builder.values().append_value(util::value_to_text(element));
+                    }
+                }
+                builder.append(true);
+            }
+            Ok(Arc::new(builder.finish()))
+        }
+        (ColumnType::String, _) => Ok(Arc::new(
+            values.map(|value| value.as_str()).collect::<StringArray>(),
+        )),
+        (ColumnType::Integer, _) => Ok(Arc::new(
+            values.map(|value| value.as_i64()).collect::<Int64Array>(),
+        )),
+        (ColumnType::Timestamp, _) => {
+            let mut timestamps = vec![];
+            for value in values {
+                timestamps.push(match value {
+                    Value::Null => None,
+                    _ => Some(parse_timestamp(value)?.timestamp_micros()),
+                });
+            }
+            Ok(Arc::new(
+                TimestampMicrosecondArray::from(timestamps).with_timezone("UTC"),
+            ))
+        }
+    };
+}
+
+fn parse_timestamp(value: &Value) -> Result<DateTime<Utc>, Box<dyn Error>> {
+    let text = value.as_str().ok_or("A timestamp must be a string")?;
+    return Ok(DateTime::parse_from_rfc3339(text)?.with_timezone(&Utc));
+}
+
+pub fn new(dir: &str, partition_actions_by_month: bool) -> ParquetFileSink {
+    return ParquetFileSink {
+        dir: PathBuf::from(dir),
+        partition_actions_by_month,
+        run_id: Utc::now().format("%Y%m%dT%H%M%S%.9fZ").to_string(),
+    };
+}
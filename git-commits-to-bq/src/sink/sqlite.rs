@@ -0,0 +1,509 @@
+use chrono::prelude::*;
+use rusqlite::types::Value as SqlValue;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Serialize;
+use serde_json::Value;
+use std::error::Error;
+
+use super::{util, Sink, Table};
+use crate::checkpoint::{Checkpoint, CheckpointStore};
+use crate::converter::{self, Column, ColumnMode, ColumnType, MergeRule, RowKey, TableSchema};
+
+// Writes every table into a single SQLite database file, along with the checkpoint,
+// so that the file is a self-contained archive of the history
+pub struct SqliteSink {
+    connection: Connection,
+}
+
+// A table a repeated column is normalized into, with a row per element
+struct BridgeTable {
+    name: &'static str,
+    parent_column: &'static str,
+    element_column: &'static str,
+}
+
+impl SqliteSink {
+    fn create_table<T: TableSchema>(&self, table: Table) -> Result<(), Box<dyn Error>> {
+        let key_columns = key_columns(table);
+        let mut definitions = vec![];
+        let mut statements = vec![];
+        for column in &T::columns() {
+            match bridge_table(table, column.name) {
+                Some(bridge_table) => statements.push(format!(
+                    "CREATE TABLE IF NOT EXISTS {} ({} TEXT NOT NULL, position INTEGER NOT NULL, {} TEXT NOT NULL, PRIMARY KEY ({}, position));",
+                    bridge_table.name,
+                    bridge_table.parent_column,
+                    bridge_table.element_column,
+                    bridge_table.parent_column
+                )),
+                None => definitions.push(column_definition(
+                    column,
+                    key_columns.contains(&column.name)
+                        && !nullable_key_columns(table).contains(&column.name),
+                )),
+            }
+        }
+        // NULLs never conflict in a primary key, so a key with a nullable column is a unique index instead
+        if nullable_key_columns(table).is_empty() {
+            definitions.push(format!("PRIMARY KEY ({})", key_columns.join(", ")));
+        } else {
+            statements.push(format!(
+                "CREATE UNIQUE INDEX IF NOT EXISTS {}_key ON {} ({});",
+                table.name(),
+                table.name(),
+                key_expressions(table).join(", ")
+            ));
+        }
+        statements.insert(
+            0,
+            format!(
+                "CREATE TABLE IF NOT EXISTS {} ({});",
+                table.name(),
+                definitions.join(", ")
+            ),
+        );
+        self.connection.execute_batch(&statements.join("\n"))?;
+        return Ok(());
+    }
+}
+
+impl Sink for SqliteSink {
+    async fn prepare(&self) -> Result<(), Box<dyn Error>> {
+        self.create_table::<converter::ActionTableRow>(Table::Action)?;
+        self.create_table::<converter::TrackTableRow>(Table::Track)?;
+        self.create_table::<converter::ArtistTableRow>(Table::Artist)?;
+        self.create_table::<converter::TrackHistoryTableRow>(Table::TrackHistory)?;
+        self.create_table::<converter::PlaylistActionTableRow>(Table::PlaylistAction)?;
+        self.create_table::<converter::PlaylistTableRow>(Table::Playlist)?;
+        self.create_table::<converter::ConversionErrorTableRow>(Table::ConversionError)?;
+        // A single row, replaced after every sync
+        self.connection.execute(
+            "CREATE TABLE IF NOT EXISTS sync_checkpoint (id INTEGER PRIMARY KEY CHECK (id = 0), sha TEXT NOT NULL, datetime TEXT NOT NULL)",
+            [],
+        )?;
+        return Ok(());
+    }
+
+    // The checkpoint is kept, and replaced once the history has been written again
+    async fn truncate(&self) -> Result<(), Box<dyn Error>> {
+        let mut statements = vec![];
+        for table in Table::ALL {
+            for column in table.columns() {
+                if let Some(bridge_table) = bridge_table(table, column.name) {
+                    statements.push(format!("DELETE FROM {};", bridge_table.name));
+                }
+            }
+            statements.push(format!("DELETE FROM {};", table.name()));
+        }
+        let transaction = self.connection.unchecked_transaction()?;
+        transaction.execute_batch(&statements.join("\n"))?;
+        transaction.commit()?;
+        return Ok(());
+    }
+
+    // Upserts the rows on the key of the table, so that a rerun over the same commits changes nothing
+    async fn write_rows<T>(&self, table: Table, rows: Vec<T>) -> Result<(), Box<dyn Error>>
+    where
+        T: Serialize + RowKey + TableSchema,
+    {
+        if rows.is_empty() {
+            return Ok(());
+        }
+        let columns = T::columns();
+        let bridge_tables: Vec<(usize, BridgeTable)> = columns
+            .iter()
+            .enumerate()
+            .filter_map(|(index, column)| {
+                bridge_table(table, column.name).map(|bridge_table| (index, bridge_table))
+            })
+            .collect();
+        let stored_indices: Vec<usize> = (0..columns.len())
+            .filter(|index| {
+                bridge_tables
+                    .iter()
+                    .all(|(bridged_index, _)| bridged_index != index)
+            })
+            .collect();
+        let stored_columns: Vec<&Column> = stored_indices
+            .iter()
+            .map(|&index| &columns[index])
+            .collect();
+        // A table with a bridge table is keyed on a single column
+        let key_index = columns
+            .iter()
+            .position(|column| column.name == key_columns(table)[0])
+            .ok_or("The key column is not a column of the table")?;
+
+        // All or none of the rows are written
+        let transaction = self.connection.unchecked_transaction()?;
+        {
+            let mut upsert = transaction.prepare(&upsert_statement::<T>(table, &stored_columns))?;
+            for row in &rows {
+                let values = util::row_to_values(row, &columns)?;
+                upsert.execute(rusqlite::params_from_iter(
+                    stored_indices
+                        .iter()
+                        .map(|&index| json_to_sql(&values[index])),
+                ))?;
+                for (index, bridge_table) in &bridge_tables {
+                    write_bridge_rows(
+                        &transaction,
+                        bridge_table,
+                        &values[key_index],
+                        &values[*index],
+                    )?;
+                }
+            }
+        }
+        if table == Table::TrackHistory {
+            remove_unchanged_versions(&transaction)?;
+            close_superseded_versions(&transaction)?;
+        }
+        transaction.commit()?;
+        println!("Wrote {} rows into {}", rows.len(), table.name());
+        return Ok(());
+    }
+
+    fn checkpoint_store(&self) -> Option<&dyn CheckpointStore> {
+        return Some(self);
+    }
+}
+
+impl CheckpointStore for SqliteSink {
+    fn load(&self) -> Result<Option<Checkpoint>, Box<dyn Error>> {
+        let row = self
+            .connection
+            .query_row(
+                "SELECT sha, datetime FROM sync_checkpoint WHERE id = 0",
+                [],
+                |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)),
+            )
+            .optional()?;
+        return match row {
+            Some((sha, datetime)) => Ok(Some(Checkpoint {
+                sha,
+                datetime: DateTime::parse_from_rfc3339(&datetime)?.with_timezone(&Utc),
+            })),
+            None => Ok(None),
+        };
+    }
+
+    fn save(&self, checkpoint: &Checkpoint) -> Result<(), Box<dyn Error>> {
+        self.connection.execute(
+            "INSERT INTO sync_checkpoint (id, sha, datetime) VALUES (0, ?1, ?2) ON CONFLICT (id) DO UPDATE SET sha = excluded.sha, datetime = excluded.datetime",
+            params![checkpoint.sha, checkpoint.datetime.to_rfc3339()],
+        )?;
+        return Ok(());
+    }
+}
+
+// The columns identifying a row
+fn key_columns(table: Table) -> &'static [&'static str] {
+    return match table {
+        Table::Action => &[
+            "commit_sha",
+            "track_id",
+            "action_type",
+            "source_playlist_id",
+            "source_position",
+            "destination_playlist_id",
+            "destination_position",
+        ],
+        Table::Track | Table::Artist | Table::Playlist => &["id"],
+        Table::TrackHistory => &["id", "valid_from"],
+        Table::PlaylistAction => &["commit_sha", "playlist_id"],
+        Table::ConversionError => &["commit_sha", "kind"],
+    };
+}
+
+// The key columns that are NULL in some rows, e.g. the source playlist of an addition
+fn nullable_key_columns(table: Table) -> &'static [&'static str] {
+    return match table {
+        Table::Action => &[
+            "source_playlist_id",
+            "source_position",
+            "destination_playlist_id",
+            "destination_position",
+        ],
+        _ => &[],
+    };
+}
+
+// The key columns, with the nullable ones compared as empty strings
+fn key_expressions(table: Table) -> Vec<String> {
+    return key_columns(table)
+        .iter()
+        .map(|name| {
+            if nullable_key_columns(table).contains(name) {
+                format!("IFNULL({}, '')", name)
+            } else {
+                name.to_string()
+            }
+        })
+        .collect();
+}
+
+// The other repeated columns are stored as JSON arrays, which can be expanded with json_each
+fn bridge_table(table: Table, column_name: &str) -> Option<BridgeTable> {
+    return match (table, column_name) {
+        (Table::Track, "artist_ids") => Some(BridgeTable {
+            name: "track_artists",
+            parent_column: "track_id",
+            element_column: "artist_id",
+        }),
+        _ => None,
+    };
+}
+
+// Timestamps are stored as RFC 3339 text, which sorts chronologically
+fn column_definition(column: &Column, is_key: bool) -> String {
+    let sql_type = match (column.column_type, column.mode) {
+        (_, ColumnMode::Repeated) => "TEXT",
+        (ColumnType::String, _) | (ColumnType::Timestamp, _) => "TEXT",
+        (ColumnType::Integer, _) => "INTEGER",
+    };
+    let constraint = match column.mode {
+        _ if is_key => " NOT NULL",
+        ColumnMode::Required => " NOT NULL",
+        ColumnMode::Nullable | ColumnMode::Repeated => "",
+    };
+    return format!("{} {}{}", column.name, sql_type, constraint);
+}
+
+// Inserts a row, or updates the existing row with the same key by the merge rules of the columns
+fn upsert_statement<T: TableSchema>(table: Table, columns: &[&Column]) -> String {
+    let key_columns = key_columns(table);
+    let column_names: Vec<&str> = columns.iter().map(|column| column.name).collect();
+    let placeholders: Vec<String> = (1..=columns.len())
+        .map(|index| format!("?{}", index))
+        .collect();
+    let assignments: Vec<String> = column_names
+        .iter()
+        .filter(|name| !key_columns.contains(name))
+        .map(|name| match T::merge_rule(name) {
+            MergeRule::Replace => format!("{} = excluded.{}", name, name),
+            MergeRule::KeepMin => format!("{} = MIN({}, excluded.{})", name, name, name),
+            MergeRule::KeepMax => format!("{} = MAX({}, excluded.{})", name, name, name),
+        })
+        .collect();
+    let conflict_action = if assignments.is_empty() {
+        "DO NOTHING".to_string()
+    } else {
+        format!("DO UPDATE SET {}", assignments.join(", "))
+    };
+    return format!(
+        "INSERT INTO {} ({}) VALUES ({}) ON CONFLICT ({}) {}",
+        table.name(),
+        column_names.join(", "),
+        placeholders.join(", "),
+        key_expressions(table).join(", "),
+        conflict_action
+    );
+}
+
+// Replaces the elements of a row in the bridge table
+fn write_bridge_rows(
+    connection: &Connection,
+    bridge_table: &BridgeTable,
+    parent_key: &Value,
+    elements: &Value,
+) -> Result<(), Box<dyn Error>> {
+    let parent_key = json_to_sql(parent_key);
+    connection.execute(
+        &format!(
+            "DELETE FROM {} WHERE {} = ?1",
+            bridge_table.name, bridge_table.parent_column
+        ),
+        params![parent_key],
+    )?;
+    let elements = match elements {
+        Value::Array(elements) => elements.as_slice(),
+        _ => &[],
+    };
+    for (position, element) in elements.iter().enumerate() {
+        connection.execute(
+            &format!(
+                "INSERT INTO {} ({}, position, {}) VALUES (?1, ?2, ?3)",
+                bridge_table.name, bridge_table.parent_column, bridge_table.element_column
+            ),
+            params![parent_key, position as i64, json_to_sql(element)],
+        )?;
+    }
+    return Ok(());
+}
+
+// Deletes each track version with the same name and artists as the previous version of the track,
+// e.g. one written by a later run for a track added to another playlist
+fn remove_unchanged_versions(connection: &Connection) -> Result<(), Box<dyn Error>> {
+    connection.execute(
+        "DELETE FROM track_history WHERE EXISTS (
+            SELECT 1 FROM track_history AS previous
+            WHERE previous.id = track_history.id
+            AND previous.valid_from = (
+                SELECT MAX(earlier.valid_from) FROM track_history AS earlier
+                WHERE earlier.id = track_history.id AND earlier.valid_from < track_history.valid_from
+            )
+            AND previous.name = track_history.name AND previous.artist_ids = track_history.artist_ids
+        )",
+        [],
+    )?;
+    return Ok(());
+}
+
+// Ends each track version where the next version of the track, possibly from a later run, starts.
+// Every version is updated, since removing a repeated version reopens the one before it.
+fn close_superseded_versions(connection: &Connection) -> Result<(), Box<dyn Error>> {
+    connection.execute(
+        "UPDATE track_history SET valid_to = (
+            SELECT MIN(later.valid_from) FROM track_history AS later
+            WHERE later.id = track_history.id AND later.valid_from > track_history.valid_from
+        )",
+        [],
+    )?;
+    return Ok(());
+}
+
+fn json_to_sql(value: &Value) -> SqlValue {
+    return match value {
+        Value::Null => SqlValue::Null,
+        Value::Number(number) => match number.as_i64() {
+            Some(integer) => SqlValue::Integer(integer),
+            None => SqlValue::Real(number.as_f64().unwrap_or_default()),
+        },
+        _ => SqlValue::Text(util::value_to_text(value)),
+    };
+}
+
+pub fn new(path: &str) -> Result<SqliteSink, Box<dyn Error>> {
+    return Ok(SqliteSink {
+        connection: Connection::open(path)?,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::converter::{ActionTableRow, PlaylistTableRow, TrackHistoryTableRow};
+
+    fn upsert_statement_of<T: TableSchema>(table: Table) -> String {
+        let columns = T::columns();
+        let stored_columns: Vec<&Column> = columns.iter().collect();
+        return upsert_statement::<T>(table, &stored_columns);
+    }
+
+    fn addition(destination_playlist_id: &str, destination_position: usize) -> ActionTableRow {
+        return ActionTableRow {
+            commit_sha: "c1".to_string(),
+            timestamp: "2019-10-04T00:00:00+00:00".to_string(),
+            action_type: "addition".to_string(),
+            source_playlist_id: None,
+            destination_playlist_id: Some(destination_playlist_id.to_string()),
+            track_id: "t1".to_string(),
+            source_position: None,
+            destination_position: Some(destination_position),
+            added_at: None,
+        };
+    }
+
+    fn track_version(name: &str, valid_from: &str) -> TrackHistoryTableRow {
+        return TrackHistoryTableRow {
+            id: "t1".to_string(),
+            name: name.to_string(),
+            artist_ids: vec!["a1".to_string()],
+            valid_from: valid_from.to_string(),
+            valid_to: None,
+        };
+    }
+
+    async fn prepared_sink() -> SqliteSink {
+        let sink = new(":memory:").unwrap();
+        sink.prepare().await.unwrap();
+        return sink;
+    }
+
+    #[test]
+    fn upsert_statement_updates_the_columns_by_their_rules() {
+        assert_eq!(
+            upsert_statement_of::<PlaylistTableRow>(Table::Playlist),
+            "INSERT INTO playlists (id, name, first_seen_at, last_seen_at) VALUES (?1, ?2, ?3, ?4) \
+             ON CONFLICT (id) DO UPDATE SET name = excluded.name, \
+             first_seen_at = MIN(first_seen_at, excluded.first_seen_at), \
+             last_seen_at = MAX(last_seen_at, excluded.last_seen_at)"
+        );
+    }
+
+    #[test]
+    fn upsert_statement_compares_the_nullable_key_columns_as_empty_strings() {
+        let statement = upsert_statement_of::<ActionTableRow>(Table::Action);
+        assert!(statement.ends_with(
+            "ON CONFLICT (commit_sha, track_id, action_type, \
+             IFNULL(source_playlist_id, ''), IFNULL(source_position, ''), \
+             IFNULL(destination_playlist_id, ''), IFNULL(destination_position, '')) \
+             DO UPDATE SET timestamp = excluded.timestamp, added_at = excluded.added_at"
+        ));
+    }
+
+    #[tokio::test]
+    async fn write_rows_twice_keeps_one_row_per_key() {
+        let sink = prepared_sink().await;
+        // Two copies of the track in p1, and one in p2
+        for _ in 0..2 {
+            sink.write_rows(
+                Table::Action,
+                vec![addition("p1", 0), addition("p1", 2), addition("p2", 0)],
+            )
+            .await
+            .unwrap();
+        }
+        let row_count: i64 = sink
+            .connection
+            .query_row("SELECT COUNT(*) FROM actions", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(row_count, 3);
+    }
+
+    #[tokio::test]
+    async fn write_rows_removes_the_track_versions_repeated_by_a_later_run() {
+        let sink = prepared_sink().await;
+        sink.write_rows(
+            Table::TrackHistory,
+            vec![track_version("Song", "2019-10-04T00:00:00+00:00")],
+        )
+        .await
+        .unwrap();
+        sink.write_rows(
+            Table::TrackHistory,
+            vec![
+                track_version("Song", "2019-10-05T00:00:00+00:00"),
+                track_version("Song (Remaster)", "2019-10-06T00:00:00+00:00"),
+            ],
+        )
+        .await
+        .unwrap();
+
+        let mut statement = sink
+            .connection
+            .prepare("SELECT name, valid_from, valid_to FROM track_history ORDER BY valid_from")
+            .unwrap();
+        let versions: Vec<(String, String, Option<String>)> = statement
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            versions,
+            vec![
+                (
+                    "Song".to_string(),
+                    "2019-10-04T00:00:00+00:00".to_string(),
+                    Some("2019-10-06T00:00:00+00:00".to_string())
+                ),
+                (
+                    "Song (Remaster)".to_string(),
+                    "2019-10-06T00:00:00+00:00".to_string(),
+                    None
+                ),
+            ]
+        );
+    }
+}
@@ -0,0 +1,46 @@
+use serde::Serialize;
+use serde_json::Value;
+use std::error::Error;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+use crate::converter::Column;
+
+// The values of a row in the order of the columns
+pub fn row_to_values<T: Serialize>(
+    row: &T,
+    columns: &[Column],
+) -> Result<Vec<Value>, Box<dyn Error>> {
+    let mut fields = match serde_json::to_value(row)? {
+        Value::Object(fields) => fields,
+        _ => return Err(From::from("A row must be serialized into an object")),
+    };
+    return Ok(columns
+        .iter()
+        .map(|column| fields.remove(column.name).unwrap_or(Value::Null))
+        .collect());
+}
+
+// Renders a value as a flat text cell; a repeated column becomes a JSON array
+pub fn value_to_text(value: &Value) -> String {
+    return match value {
+        Value::Null => String::new(),
+        Value::String(s) => s.to_string(),
+        _ => value.to_string(),
+    };
+}
+
+// Removes a file or a directory with everything in it; a missing one is already removed
+pub fn remove_path(path: &Path) -> Result<(), Box<dyn Error>> {
+    let result = if path.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    };
+    return match result {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(Box::new(e)),
+    };
+}
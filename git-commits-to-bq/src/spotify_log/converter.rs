@@ -1,98 +1,260 @@
-use chrono::prelude::*;
-use lazy_static::lazy_static;
+use std::collections::HashSet;
 
 use crate::github_client;
-use crate::spotify_log::defs::{TrackRelatedAction, TrackRelatedActionType};
+use crate::github_client::defs::{CommitFile, DiffType};
+use crate::spotify_log::defs::{
+    ConversionError, ConversionErrorKind, LogFilter, MismatchedCommit, Playlist, PlaylistAction,
+    PlaylistActionType, Track, TrackRelatedAction, TrackRelatedActionType,
+};
 use crate::spotify_log::parser;
 use crate::spotify_log::util;
 
-const LOG_COMMITTER_NAME: &str = "GitHub Actions";
+pub fn is_log_commit(commit: &github_client::defs::Commit, log_filter: &LogFilter) -> bool {
+    // The committer must be the backup job
+    if commit.committer_name != log_filter.committer_name {
+        return false;
+    }
+
+    // The commit must be made after the backup job started to work
+    if commit.datetime < log_filter.started_at {
+        return false;
+    }
 
-lazy_static! {
-    static ref LOG_STARTED_AT: DateTime<Utc> =
-        "2019-10-03T00:00:00Z".parse::<DateTime<Utc>>().unwrap();
+    return true;
 }
 
-// Returns None when the commit is not related to a track
-pub fn commit_to_track_related_action(
+fn conversion_error(
     commit: &github_client::defs::Commit,
-) -> Option<TrackRelatedAction> {
-    // The committer must be GitHub Actions
-    if commit.committer_name != LOG_COMMITTER_NAME {
-        return None;
-    }
+    kind: ConversionErrorKind,
+) -> ConversionError {
+    return ConversionError {
+        commit_sha: commit.sha.to_string(),
+        message: commit.message.to_string(),
+        datetime: commit.datetime,
+        kind,
+    };
+}
 
-    // The commit must be made after the backup job started to work
-    if commit.datetime < *LOG_STARTED_AT {
-        return None;
-    }
+// Parses the content of the file before or after the commit
+fn parse_playlist_snapshot(
+    commit: &github_client::defs::Commit,
+    file: &CommitFile,
+    content: &str,
+) -> Result<Playlist, ConversionError> {
+    return parser::parse_playlist_snapshot(content).map_err(|e| {
+        conversion_error(
+            commit,
+            ConversionErrorKind::InvalidSnapshot {
+                filename: file.filename.to_string(),
+                reason: e.to_string(),
+            },
+        )
+    });
+}
 
-    // The action target must be a track
-    let action_type = parser::commit_message_to_track_related_action_type(&commit.message)?;
+// Returns None when the commit is not related to a playlist itself
+pub fn commit_to_playlist_action(
+    commit: &github_client::defs::Commit,
+) -> Result<Option<PlaylistAction>, ConversionError> {
+    // The action target must be a playlist
+    let action_type = match parser::commit_message_to_playlist_action_type(&commit.message) {
+        Some(action_type) => action_type,
+        None => return Ok(None),
+    };
+    let file = commit
+        .files
+        .first()
+        .ok_or_else(|| conversion_error(commit, ConversionErrorKind::MissingSnapshot))?;
 
     // Construct the action
     let action = match action_type {
-        TrackRelatedActionType::Addition => {
-            let before_playlist = parser::parse_playlist_snapshot(&commit.files[0].before).ok()?;
-            let after_playlist = parser::parse_playlist_snapshot(&commit.files[0].after).ok()?;
-            let extra_track = util::identify_extra_track(&before_playlist, &after_playlist)?;
-            TrackRelatedAction {
+        PlaylistActionType::Creation => {
+            let after_playlist = parse_playlist_snapshot(commit, file, &file.after)?;
+            PlaylistAction {
+                commit_sha: commit.sha.to_string(),
                 datetime: commit.datetime,
                 action_type,
-                source_playlist_id: None,
-                destination_playlist_id: Some(after_playlist.id),
-                track: extra_track,
+                playlist_id: after_playlist.id,
+                name: after_playlist.name,
+                previous_name: None,
             }
         }
-        TrackRelatedActionType::Removal => {
-            let before_playlist = parser::parse_playlist_snapshot(&commit.files[0].before).ok()?;
-            let after_playlist = parser::parse_playlist_snapshot(&commit.files[0].after).ok()?;
-            let extra_track = util::identify_extra_track(&before_playlist, &after_playlist)?;
-            TrackRelatedAction {
+        PlaylistActionType::Deletion => {
+            let before_playlist = parse_playlist_snapshot(commit, file, &file.before)?;
+            PlaylistAction {
+                commit_sha: commit.sha.to_string(),
                 datetime: commit.datetime,
                 action_type,
-                source_playlist_id: Some(before_playlist.id),
-                destination_playlist_id: None,
-                track: extra_track,
+                playlist_id: before_playlist.id,
+                name: before_playlist.name,
+                previous_name: None,
             }
         }
-        TrackRelatedActionType::Transfer => {
-            let (source_file, destination_file) = if commit.files[0].added_line_count > 0 {
-                (&commit.files[1], &commit.files[0])
-            } else {
-                (&commit.files[0], &commit.files[1])
-            };
-            let before_source_playlist =
-                parser::parse_playlist_snapshot(&source_file.before).ok()?;
-            let before_destination_playlist =
-                parser::parse_playlist_snapshot(&destination_file.before).ok()?;
-            let after_destination_playlist =
-                parser::parse_playlist_snapshot(&destination_file.after).ok()?;
-            let extra_track = util::identify_extra_track(
-                &before_destination_playlist,
-                &after_destination_playlist,
-            )?;
-            TrackRelatedAction {
+        PlaylistActionType::Modification => {
+            let before_playlist = parse_playlist_snapshot(commit, file, &file.before)?;
+            let after_playlist = parse_playlist_snapshot(commit, file, &file.after)?;
+            PlaylistAction {
+                commit_sha: commit.sha.to_string(),
                 datetime: commit.datetime,
                 action_type,
-                source_playlist_id: Some(before_source_playlist.id),
-                destination_playlist_id: Some(after_destination_playlist.id),
-                track: extra_track,
+                playlist_id: after_playlist.id,
+                name: after_playlist.name,
+                previous_name: Some(before_playlist.name),
             }
         }
-        TrackRelatedActionType::Modification => {
-            let before_playlist = parser::parse_playlist_snapshot(&commit.files[0].before).ok()?;
-            let after_playlist = parser::parse_playlist_snapshot(&commit.files[0].after).ok()?;
-            let modified_track = util::identify_modified_track(&before_playlist, &after_playlist)?;
-            TrackRelatedAction {
-                datetime: commit.datetime,
-                action_type,
-                source_playlist_id: Some((&after_playlist.id).to_string()),
-                destination_playlist_id: Some((&after_playlist.id).to_string()),
-                track: modified_track,
-            }
+    };
+
+    return Ok(Some(action));
+}
+
+// Returns every track change in the commit, whatever its message says.
+// A track removed from one playlist and added to another in the same commit is a transfer.
+// Returns an empty Vec when the commit is not related to a track.
+pub fn commit_to_track_related_actions(
+    commit: &github_client::defs::Commit,
+) -> Result<Vec<TrackRelatedAction>, ConversionError> {
+    // The action target must be a track
+    if parser::commit_message_to_track_related_action_type(&commit.message).is_none() {
+        return Ok(vec![]);
+    }
+
+    // Diff every playlist snapshot the commit modified
+    let mut playlist_diffs = vec![];
+    for file in &commit.files {
+        if !matches!(file.diff_type, DiffType::Modification) {
+            continue;
         }
+        let before_playlist = parse_playlist_snapshot(commit, file, &file.before)?;
+        let after_playlist = parse_playlist_snapshot(commit, file, &file.after)?;
+        let diff = util::diff_playlists(&before_playlist, &after_playlist);
+        playlist_diffs.push((after_playlist, diff));
+    }
+    if playlist_diffs.is_empty() {
+        return Err(conversion_error(
+            commit,
+            ConversionErrorKind::MissingSnapshot,
+        ));
+    }
+
+    // Construct the actions.
+    // The source and the destination are given as pairs of a playlist and the track index in it.
+    let new_action = |action_type: TrackRelatedActionType,
+                      source: Option<(&Playlist, Option<usize>)>,
+                      destination: Option<(&Playlist, Option<usize>)>,
+                      track: &Track| TrackRelatedAction {
+        commit_sha: commit.sha.to_string(),
+        datetime: commit.datetime,
+        action_type,
+        source_playlist_id: source.map(|(playlist, _position)| playlist.id.to_string()),
+        source_playlist_name: source.map(|(playlist, _position)| playlist.name.to_string()),
+        destination_playlist_id: destination.map(|(playlist, _position)| playlist.id.to_string()),
+        destination_playlist_name: destination
+            .map(|(playlist, _position)| playlist.name.to_string()),
+        source_position: source.and_then(|(_playlist, position)| position),
+        destination_position: destination.and_then(|(_playlist, position)| position),
+        track: track.clone(),
     };
+    let mut actions = vec![];
+    // (destination diff index, added track index), so that each copy of a duplicated track is paired once
+    let mut transferred = HashSet::new();
+    for (source_index, (source_playlist, source_diff)) in playlist_diffs.iter().enumerate() {
+        for removed_track in &source_diff.removed_tracks {
+            let track = &removed_track.track;
+            let destination = playlist_diffs
+                .iter()
+                .enumerate()
+                .filter(|(destination_index, _)| *destination_index != source_index)
+                .find_map(
+                    |(destination_index, (destination_playlist, destination_diff))| {
+                        destination_diff
+                            .added_tracks
+                            .iter()
+                            .enumerate()
+                            .find(|(added_index, added_track)| {
+                                added_track.track.id == track.id
+                                    && !transferred.contains(&(destination_index, *added_index))
+                            })
+                            .map(|(added_index, added_track)| {
+                                (
+                                    destination_index,
+                                    added_index,
+                                    destination_playlist,
+                                    added_track,
+                                )
+                            })
+                    },
+                );
+            match destination {
+                Some((destination_index, added_index, destination_playlist, added_track)) => {
+                    transferred.insert((destination_index, added_index));
+                    actions.push(new_action(
+                        TrackRelatedActionType::Transfer,
+                        Some((source_playlist, removed_track.before_position)),
+                        Some((destination_playlist, added_track.after_position)),
+                        &added_track.track,
+                    ));
+                }
+                None => actions.push(new_action(
+                    TrackRelatedActionType::Removal,
+                    Some((source_playlist, removed_track.before_position)),
+                    None,
+                    track,
+                )),
+            }
+        }
+    }
+    for (index, (playlist, diff)) in playlist_diffs.iter().enumerate() {
+        for (added_index, added_track) in diff.added_tracks.iter().enumerate() {
+            if transferred.contains(&(index, added_index)) {
+                continue;
+            }
+            actions.push(new_action(
+                TrackRelatedActionType::Addition,
+                None,
+                Some((playlist, added_track.after_position)),
+                &added_track.track,
+            ));
+        }
+        for (action_type, track_diffs) in [
+            (TrackRelatedActionType::Modification, &diff.modified_tracks),
+            (TrackRelatedActionType::Reorder, &diff.reordered_tracks),
+        ] {
+            for track_diff in track_diffs {
+                actions.push(new_action(
+                    action_type,
+                    Some((playlist, track_diff.before_position)),
+                    Some((playlist, track_diff.after_position)),
+                    &track_diff.track,
+                ));
+            }
+        }
+    }
+
+    if actions.is_empty() {
+        return Err(conversion_error(commit, ConversionErrorKind::NoTrackChange));
+    }
+    return Ok(actions);
+}
 
-    return Some(action);
+// Returns Some when the actions read from the diff are not all of the type the message describes
+pub fn find_mismatched_commit(
+    commit: &github_client::defs::Commit,
+    actions: &[TrackRelatedAction],
+) -> Option<MismatchedCommit> {
+    let message_action_type = parser::commit_message_to_track_related_action_type(&commit.message)?;
+    let mut diff_action_types = vec![];
+    for action in actions {
+        if !diff_action_types.contains(&action.action_type) {
+            diff_action_types.push(action.action_type);
+        }
+    }
+    if diff_action_types == [message_action_type] {
+        return None;
+    }
+    return Some(MismatchedCommit {
+        commit_sha: commit.sha.to_string(),
+        message: commit.message.to_string(),
+        message_action_type,
+        diff_action_types,
+    });
 }
@@ -1,5 +1,14 @@
 use chrono::prelude::*;
 use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
+
+// Tells the commits of the backup job from the others in the repository
+#[derive(Clone, Debug)]
+pub struct LogFilter {
+    pub committer_name: String,
+    pub started_at: DateTime<Utc>, // The commits before the backup job started to work are ignored
+}
 
 #[derive(Serialize, Deserialize)]
 pub struct Playlist {
@@ -8,12 +17,27 @@ pub struct Playlist {
     pub tracks: Vec<Track>,
 }
 
+// The metadata fields are missing from some snapshots, so they are optional
 #[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct Track {
     pub id: String,
     pub name: String,
     pub artists: Vec<Artist>,
-    // Ignore addedAt property
+    #[serde(default)]
+    pub added_at: Option<DateTime<Utc>>, // When the track was added to the playlist
+    #[serde(default)]
+    pub album: Option<Album>,
+    #[serde(default)]
+    pub duration_ms: Option<u32>,
+    #[serde(default)]
+    pub popularity: Option<u8>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Album {
+    pub id: String,
+    pub name: String,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -22,19 +46,126 @@ pub struct Artist {
     pub name: String,
 }
 
+// A track in a playlist diff, with its indexes in Playlist.tracks
 #[derive(Debug)]
+pub struct TrackDiff {
+    pub track: Track, // The track after the change if it is still in the playlist
+    pub before_position: Option<usize>, // None for an added track
+    pub after_position: Option<usize>, // None for a removed track
+}
+
+// The difference between two snapshots of a playlist
+#[derive(Debug, Default)]
+pub struct PlaylistDiff {
+    pub added_tracks: Vec<TrackDiff>,
+    pub removed_tracks: Vec<TrackDiff>,
+    pub modified_tracks: Vec<TrackDiff>,
+    pub reordered_tracks: Vec<TrackDiff>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum TrackRelatedActionType {
     Addition,
     Removal,
     Transfer,
     Modification,
+    Reorder,
 }
 
 #[derive(Debug)]
 pub struct TrackRelatedAction {
+    pub commit_sha: String,
     pub datetime: DateTime<Utc>,
     pub action_type: TrackRelatedActionType,
     pub source_playlist_id: Option<String>,
+    pub source_playlist_name: Option<String>,
     pub destination_playlist_id: Option<String>,
+    pub destination_playlist_name: Option<String>,
+    pub source_position: Option<usize>, // The index in the source playlist before the action
+    pub destination_position: Option<usize>, // The index in the destination playlist after the action
     pub track: Track,
 }
+
+#[derive(Debug)]
+pub enum PlaylistActionType {
+    Creation,
+    Deletion,
+    Modification,
+}
+
+#[derive(Debug)]
+pub struct PlaylistAction {
+    pub commit_sha: String,
+    pub datetime: DateTime<Utc>,
+    pub action_type: PlaylistActionType,
+    pub playlist_id: String,
+    pub name: String,                  // The name before the deletion for a deletion
+    pub previous_name: Option<String>, // Only for a modification
+}
+
+// A commit whose snapshot diff does not agree with its message,
+// e.g. a bulk edit picked up by the backup job as a single addition
+#[derive(Debug)]
+pub struct MismatchedCommit {
+    pub commit_sha: String,
+    pub message: String,
+    pub message_action_type: TrackRelatedActionType,
+    pub diff_action_types: Vec<TrackRelatedActionType>,
+}
+
+#[derive(Debug)]
+pub enum ConversionErrorKind {
+    // The commit has no file to read the playlist snapshots from
+    MissingSnapshot,
+    // A snapshot is not the JSON of a playlist, e.g. a truncated raw download
+    InvalidSnapshot { filename: String, reason: String },
+    // The message describes a track change but the snapshots have none
+    NoTrackChange,
+}
+
+// A log commit that could not be converted into actions
+#[derive(Debug)]
+pub struct ConversionError {
+    pub commit_sha: String,
+    pub message: String,
+    pub datetime: DateTime<Utc>,
+    pub kind: ConversionErrorKind,
+}
+
+impl fmt::Display for ConversionErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return match self {
+            ConversionErrorKind::MissingSnapshot => {
+                write!(f, "the commit has no playlist snapshot")
+            }
+            ConversionErrorKind::InvalidSnapshot { filename, reason } => {
+                write!(
+                    f,
+                    "{} is not a valid playlist snapshot: {}",
+                    filename, reason
+                )
+            }
+            ConversionErrorKind::NoTrackChange => write!(f, "the snapshots have no track change"),
+        };
+    }
+}
+
+impl fmt::Display for ConversionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return write!(
+            f,
+            "Commit {} ({}): {}",
+            self.commit_sha, self.message, self.kind
+        );
+    }
+}
+
+impl Error for ConversionError {}
+
+#[derive(Debug, Default)]
+pub struct Actions {
+    pub track_related_actions: Vec<TrackRelatedAction>,
+    pub playlist_actions: Vec<PlaylistAction>,
+    pub mismatched_commits: Vec<MismatchedCommit>,
+    pub conversion_errors: Vec<ConversionError>,
+}
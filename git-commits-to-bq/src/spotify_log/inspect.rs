@@ -0,0 +1,104 @@
+use crate::github_client::defs::{Commit, CommitFile, DiffType};
+use crate::spotify_log::converter;
+use crate::spotify_log::defs::{LogFilter, TrackDiff};
+use crate::spotify_log::parser;
+use crate::spotify_log::util;
+
+// Prints how a commit is classified and diffed, and the actions it is converted into
+pub fn inspect_commit(commit: &Commit, log_filter: &LogFilter) {
+    println!("Commit {}", commit.sha);
+    println!(
+        "Committed by {} at {}",
+        commit.committer_name, commit.datetime
+    );
+    println!("Message: {}", commit.message.trim_end());
+    if !converter::is_log_commit(commit, log_filter) {
+        println!(
+            "Not a log commit by {} since {}, so it is skipped",
+            log_filter.committer_name, log_filter.started_at
+        );
+        return;
+    }
+    println!(
+        "Track action in the message: {:?}",
+        parser::commit_message_to_track_related_action_type(&commit.message)
+    );
+    println!(
+        "Playlist action in the message: {:?}",
+        parser::commit_message_to_playlist_action_type(&commit.message)
+    );
+
+    for file in &commit.files {
+        inspect_file(file);
+    }
+
+    match converter::commit_to_track_related_actions(commit) {
+        Ok(actions) => {
+            if let Some(mismatched_commit) = converter::find_mismatched_commit(commit, &actions) {
+                println!(
+                    "The message says {:?} but the diff has {:?}",
+                    mismatched_commit.message_action_type, mismatched_commit.diff_action_types
+                );
+            }
+            for action in &actions {
+                println!(
+                    "Track action: {:?} of {} ({}) from {:?} at {:?} to {:?} at {:?}",
+                    action.action_type,
+                    action.track.id,
+                    action.track.name,
+                    action.source_playlist_id,
+                    action.source_position,
+                    action.destination_playlist_id,
+                    action.destination_position
+                );
+            }
+        }
+        Err(e) => println!("Track actions cannot be converted: {}", e),
+    }
+    match converter::commit_to_playlist_action(commit) {
+        Ok(Some(action)) => println!(
+            "Playlist action: {:?} of {} ({}), previously {:?}",
+            action.action_type, action.playlist_id, action.name, action.previous_name
+        ),
+        Ok(None) => {}
+        Err(e) => println!("The playlist action cannot be converted: {}", e),
+    }
+}
+
+fn inspect_file(file: &CommitFile) {
+    let diff_type = match file.diff_type {
+        DiffType::Addition => "added",
+        DiffType::Deletion => "deleted",
+        DiffType::Modification => "modified",
+        DiffType::Unknown => "changed",
+    };
+    println!("File {} ({})", file.filename, diff_type);
+    if !matches!(file.diff_type, DiffType::Modification) {
+        return;
+    }
+    let before_playlist = parser::parse_playlist_snapshot(&file.before);
+    let after_playlist = parser::parse_playlist_snapshot(&file.after);
+    let (before_playlist, after_playlist) = match (before_playlist, after_playlist) {
+        (Ok(before_playlist), Ok(after_playlist)) => (before_playlist, after_playlist),
+        (Err(e), _) => return println!("  The snapshot before the commit cannot be parsed: {}", e),
+        (_, Err(e)) => return println!("  The snapshot after the commit cannot be parsed: {}", e),
+    };
+    let diff = util::diff_playlists(&before_playlist, &after_playlist);
+    print_track_diffs("Added", &diff.added_tracks);
+    print_track_diffs("Removed", &diff.removed_tracks);
+    print_track_diffs("Modified", &diff.modified_tracks);
+    print_track_diffs("Reordered", &diff.reordered_tracks);
+}
+
+fn print_track_diffs(label: &str, track_diffs: &[TrackDiff]) {
+    for track_diff in track_diffs {
+        println!(
+            "  {}: {} ({}) at {:?} -> {:?}",
+            label,
+            track_diff.track.id,
+            track_diff.track.name,
+            track_diff.before_position,
+            track_diff.after_position
+        );
+    }
+}
@@ -1,29 +1,114 @@
+use chrono::prelude::*;
+use futures::stream::{self, StreamExt};
 use std::error::Error;
 
+use crate::checkpoint::Checkpoint;
+use crate::commit_source::CommitSource;
 use crate::github_client::defs::Commit;
-use crate::github_client::GithubClient;
 
 mod converter;
 pub mod defs;
+mod inspect;
 mod parser;
 mod util;
 
-pub async fn fetch_track_related_actions(
-    github_client: &GithubClient,
-) -> Result<Vec<defs::TrackRelatedAction>, Box<dyn Error>> {
-    let commit_shas = github_client.fetch_commit_shas();
-    let mut results = vec![];
-    for sha in &commit_shas {
-        let result = github_client.fetch_commit_by_sha(sha).await;
-        results.push(result);
+pub use inspect::inspect_commit;
+
+// Print the progress every this number of commits
+const PROGRESS_INTERVAL: usize = 100;
+
+// The committer of the original backup job and when it started to work
+pub const DEFAULT_LOG_COMMITTER_NAME: &str = "GitHub Actions";
+pub const DEFAULT_LOG_STARTED_AT: &str = "2019-10-03T00:00:00Z";
+
+// Only the commits after the checkpoint are converted when it is given.
+// Up to `concurrency` commits are fetched at once, and each commit is converted and dropped
+// in commit order as soon as it arrives, so that the snapshots are not all kept in memory.
+// Also returns the last fetched commit as the next checkpoint.
+pub async fn fetch_actions(
+    commit_source: &impl CommitSource,
+    log_filter: &defs::LogFilter,
+    checkpoint: Option<&Checkpoint>,
+    concurrency: usize,
+) -> Result<(defs::Actions, Option<Checkpoint>), Box<dyn Error>> {
+    // Only the commits since the checkpoint are listed. The API includes the second of `since`,
+    // so the checkpoint commit itself is listed and found below.
+    let since = checkpoint.map(|checkpoint| checkpoint.datetime);
+    let commit_shas = commit_source.fetch_commit_shas(since).await?;
+    let (commit_shas, synced_until) = match checkpoint {
+        Some(checkpoint) => match commit_shas.iter().position(|sha| *sha == checkpoint.sha) {
+            Some(index) => (commit_shas[index + 1..].to_vec(), None),
+            // The checkpoint commit is no longer in the history (e.g. after a force push),
+            // so fall back to comparing the commit timestamps
+            None => {
+                println!(
+                    "The checkpoint commit {} is not in the history; converting the commits after {} instead",
+                    checkpoint.sha, checkpoint.datetime
+                );
+                (commit_shas, Some(checkpoint.datetime))
+            }
+        },
+        None => (commit_shas, None),
+    };
+    println!("Fetching {} commits", commit_shas.len());
+
+    let mut actions = defs::Actions::default();
+    let mut next_checkpoint = None;
+    // buffered() yields the results in the order of the SHAs
+    let mut commits = stream::iter(commit_shas.iter())
+        .map(|sha| commit_source.fetch_commit_by_sha(sha))
+        .buffered(concurrency.max(1));
+    let mut fetched_commit_count = 0;
+    while let Some(result) = commits.next().await {
+        let commit = result?;
+        fetched_commit_count += 1;
+        if fetched_commit_count % PROGRESS_INTERVAL == 0
+            || fetched_commit_count == commit_shas.len()
+        {
+            println!(
+                "Fetched {}/{} commits",
+                fetched_commit_count,
+                commit_shas.len()
+            );
+        }
+        if !is_after(&commit, synced_until) {
+            continue;
+        }
+        convert_commit(&commit, log_filter, &mut actions);
+        next_checkpoint = Some(Checkpoint {
+            sha: commit.sha.to_string(),
+            datetime: commit.datetime,
+        });
     }
-    let commits = results
-        .into_iter()
-        // Aggregate Results into a single Result
-        .collect::<Result<Vec<Commit>, Box<dyn Error>>>()?;
-    let actions = commits
-        .iter()
-        .flat_map(|commit| converter::commit_to_track_related_action(&commit))
-        .collect();
-    return Ok(actions);
+    return Ok((actions, next_checkpoint));
+}
+
+// A commit that fails to convert is collected into the report instead of failing the run.
+// The commits other than the log are skipped, but still move the checkpoint.
+fn convert_commit(commit: &Commit, log_filter: &defs::LogFilter, actions: &mut defs::Actions) {
+    if !converter::is_log_commit(commit, log_filter) {
+        return;
+    }
+    match converter::commit_to_track_related_actions(commit) {
+        Ok(track_related_actions) => {
+            if let Some(mismatched_commit) =
+                converter::find_mismatched_commit(commit, &track_related_actions)
+            {
+                actions.mismatched_commits.push(mismatched_commit);
+            }
+            actions.track_related_actions.extend(track_related_actions);
+        }
+        Err(e) => actions.conversion_errors.push(e),
+    }
+    match converter::commit_to_playlist_action(commit) {
+        Ok(playlist_action) => actions.playlist_actions.extend(playlist_action),
+        Err(e) => actions.conversion_errors.push(e),
+    }
+}
+
+fn is_after(commit: &Commit, datetime: Option<DateTime<Utc>>) -> bool {
+    return match datetime {
+        Some(datetime) => commit.datetime > datetime,
+        None => true,
+    };
 }
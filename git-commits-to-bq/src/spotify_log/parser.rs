@@ -1,9 +1,8 @@
 use lazy_static::lazy_static;
 use regex::Regex;
-use serde_json;
 use std::error::Error;
 
-use crate::spotify_log::defs::{Playlist, TrackRelatedActionType};
+use crate::spotify_log::defs::{Playlist, PlaylistActionType, TrackRelatedActionType};
 
 lazy_static! {
     // Match with these regex from the top; they are not mutually exclusive
@@ -41,6 +40,20 @@ pub fn commit_message_to_track_related_action_type(
     return None;
 }
 
+// Returns None when the commit is not related to a playlist itself
+pub fn commit_message_to_playlist_action_type(message: &str) -> Option<PlaylistActionType> {
+    if PLAYLIST_CREATION_RE.is_match(message) {
+        return Some(PlaylistActionType::Creation);
+    }
+    if PLAYLIST_DELETION_RE.is_match(message) {
+        return Some(PlaylistActionType::Deletion);
+    }
+    if PLAYLIST_MODIFICATION_RE.is_match(message) {
+        return Some(PlaylistActionType::Modification);
+    }
+    return None;
+}
+
 pub fn parse_playlist_snapshot(content: &str) -> Result<Playlist, Box<dyn Error>> {
     let parsed_result: Result<Playlist, serde_json::Error> = serde_json::from_str(content);
     return match parsed_result {
@@ -1,41 +1,93 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
-use crate::spotify_log::defs::{Playlist, Track};
+use crate::spotify_log::defs::{Playlist, PlaylistDiff, Track, TrackDiff};
 
-pub fn identify_extra_track(playlist1: &Playlist, playlist2: &Playlist) -> Option<Track> {
-  let playlist1_track_count = playlist1.tracks.len();
-  let playlist2_track_count = playlist2.tracks.len();
-  let (playlist_with_extra_track, playlist_without_extra_track) =
-    if playlist1_track_count > playlist2_track_count {
-      (playlist1, playlist2)
-    } else {
-      (playlist2, playlist1)
-    };
-  let mut track_id_to_track = HashMap::new();
-  for track in &playlist_without_extra_track.tracks {
-    track_id_to_track.insert(&track.id, track);
+// Returns every track added, removed, modified or reordered between the two snapshots
+pub fn diff_playlists(before_playlist: &Playlist, after_playlist: &Playlist) -> PlaylistDiff {
+  // A playlist can have the same track more than once, so the nth copy before is matched to the nth copy after
+  let before_track_keys = track_keys(before_playlist);
+  let after_track_keys = track_keys(after_playlist);
+  let before_track_key_to_position: HashMap<(&String, usize), usize> = before_track_keys
+    .iter()
+    .enumerate()
+    .map(|(position, &track_key)| (track_key, position))
+    .collect();
+  let after_track_key_to_position: HashMap<(&String, usize), usize> = after_track_keys
+    .iter()
+    .enumerate()
+    .map(|(position, &track_key)| (track_key, position))
+    .collect();
+
+  let mut diff = PlaylistDiff::default();
+  for (after_position, track) in after_playlist.tracks.iter().enumerate() {
+    match before_track_key_to_position.get(&after_track_keys[after_position]) {
+      Some(&before_position) => {
+        if !tracks_equal(&before_playlist.tracks[before_position], track) {
+          diff.modified_tracks.push(TrackDiff {
+            track: track.clone(),
+            before_position: Some(before_position),
+            after_position: Some(after_position),
+          });
+        }
+      }
+      None => diff.added_tracks.push(TrackDiff {
+        track: track.clone(),
+        before_position: None,
+        after_position: Some(after_position),
+      }),
+    }
   }
-  for track in &playlist_with_extra_track.tracks {
-    if track_id_to_track.get(&track.id).is_none() {
-      return Some(track.clone());
+  for (before_position, track) in before_playlist.tracks.iter().enumerate() {
+    if !after_track_key_to_position.contains_key(&before_track_keys[before_position]) {
+      diff.removed_tracks.push(TrackDiff {
+        track: track.clone(),
+        before_position: Some(before_position),
+        after_position: None,
+      });
     }
   }
-  return None;
-}
 
-pub fn identify_modified_track(playlist1: &Playlist, playlist2: &Playlist) -> Option<Track> {
-  let mut track_id_to_track = HashMap::new();
-  for track in &playlist1.tracks {
-    track_id_to_track.insert(&track.id, track);
-  }
-  for track in &playlist2.tracks {
-    if let Some(corresponding_track) = track_id_to_track.get(&track.id) {
-      if !tracks_equal(&track, corresponding_track) {
-        return Some(track.clone());
-      }
+  // A track is reordered when it is out of the longest run of kept tracks that stay in order,
+  // so that an addition or a removal does not count as reordering every track after it
+  let kept_positions: Vec<(usize, usize)> = before_track_keys
+    .iter()
+    .enumerate()
+    .filter_map(|(before_position, track_key)| {
+      after_track_key_to_position
+        .get(track_key)
+        .map(|&after_position| (before_position, after_position))
+    })
+    .collect();
+  let after_positions: Vec<usize> = kept_positions
+    .iter()
+    .map(|(_before_position, after_position)| *after_position)
+    .collect();
+  let in_order_indexes = longest_increasing_subsequence(&after_positions);
+  for (index, (before_position, after_position)) in kept_positions.into_iter().enumerate() {
+    if !in_order_indexes.contains(&index) {
+      diff.reordered_tracks.push(TrackDiff {
+        track: after_playlist.tracks[after_position].clone(),
+        before_position: Some(before_position),
+        after_position: Some(after_position),
+      });
     }
   }
-  return None;
+  return diff;
+}
+
+// Returns the ID of each track with the number of earlier tracks with the same ID
+fn track_keys(playlist: &Playlist) -> Vec<(&String, usize)> {
+  let mut track_id_to_count: HashMap<&String, usize> = HashMap::new();
+  return playlist
+    .tracks
+    .iter()
+    .map(|track| {
+      let count = track_id_to_count.entry(&track.id).or_insert(0);
+      let track_key = (&track.id, *count);
+      *count += 1;
+      track_key
+    })
+    .collect();
 }
 
 pub fn tracks_equal(track1: &Track, track2: &Track) -> bool {
@@ -52,3 +104,171 @@ pub fn tracks_equal(track1: &Track, track2: &Track) -> bool {
   }
   return true;
 }
+
+// Returns the indexes of a longest strictly increasing subsequence of the values
+fn longest_increasing_subsequence(values: &[usize]) -> HashSet<usize> {
+  // tails[k] is the index of the smallest last value of an increasing subsequence of length k + 1
+  let mut tails: Vec<usize> = vec![];
+  let mut predecessors: Vec<Option<usize>> = vec![None; values.len()];
+  for (index, value) in values.iter().enumerate() {
+    let length = tails.partition_point(|&tail| values[tail] < *value);
+    if length > 0 {
+      predecessors[index] = Some(tails[length - 1]);
+    }
+    if length == tails.len() {
+      tails.push(index);
+    } else {
+      tails[length] = index;
+    }
+  }
+
+  let mut indexes = HashSet::new();
+  let mut current = tails.last().copied();
+  while let Some(index) = current {
+    indexes.insert(index);
+    current = predecessors[index];
+  }
+  return indexes;
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::spotify_log::defs::Artist;
+
+  fn track(id: &str, name: &str) -> Track {
+    return Track {
+      id: id.to_string(),
+      name: name.to_string(),
+      artists: vec![Artist {
+        id: "a1".to_string(),
+        name: "Artist".to_string(),
+      }],
+      added_at: None,
+      album: None,
+      duration_ms: None,
+      popularity: None,
+    };
+  }
+
+  fn playlist(tracks: Vec<Track>) -> Playlist {
+    return Playlist {
+      id: "p1".to_string(),
+      name: "Playlist".to_string(),
+      tracks,
+    };
+  }
+
+  // The track ID and positions of each track in the diff
+  fn positions(track_diffs: &[TrackDiff]) -> Vec<(&str, Option<usize>, Option<usize>)> {
+    return track_diffs
+      .iter()
+      .map(|track_diff| {
+        (
+          track_diff.track.id.as_str(),
+          track_diff.before_position,
+          track_diff.after_position,
+        )
+      })
+      .collect();
+  }
+
+  #[test]
+  fn diff_playlists_finds_added_removed_and_modified_tracks() {
+    let before = playlist(vec![track("t1", "One"), track("t2", "Two"), track("t3", "Three")]);
+    let after = playlist(vec![
+      track("t1", "One"),
+      track("t3", "Three (Remaster)"),
+      track("t4", "Four"),
+    ]);
+
+    let diff = diff_playlists(&before, &after);
+
+    assert_eq!(positions(&diff.added_tracks), vec![("t4", None, Some(2))]);
+    assert_eq!(positions(&diff.removed_tracks), vec![("t2", Some(1), None)]);
+    assert_eq!(positions(&diff.modified_tracks), vec![("t3", Some(2), Some(1))]);
+    assert_eq!(diff.modified_tracks[0].track.name, "Three (Remaster)");
+    // The tracks after the removed one shift but stay in order
+    assert!(diff.reordered_tracks.is_empty());
+  }
+
+  #[test]
+  fn diff_playlists_reorders_only_the_moved_track() {
+    let before = playlist(vec![
+      track("t1", "One"),
+      track("t2", "Two"),
+      track("t3", "Three"),
+      track("t4", "Four"),
+    ]);
+    let after = playlist(vec![
+      track("t2", "Two"),
+      track("t3", "Three"),
+      track("t4", "Four"),
+      track("t1", "One"),
+    ]);
+
+    let diff = diff_playlists(&before, &after);
+
+    assert!(diff.added_tracks.is_empty());
+    assert!(diff.removed_tracks.is_empty());
+    assert!(diff.modified_tracks.is_empty());
+    assert_eq!(positions(&diff.reordered_tracks), vec![("t1", Some(0), Some(3))]);
+  }
+
+  #[test]
+  fn diff_playlists_removes_the_last_copy_of_a_duplicate() {
+    let before = playlist(vec![track("t1", "One"), track("t2", "Two"), track("t1", "One")]);
+    let after = playlist(vec![track("t1", "One"), track("t2", "Two")]);
+
+    let diff = diff_playlists(&before, &after);
+
+    assert!(diff.added_tracks.is_empty());
+    assert_eq!(positions(&diff.removed_tracks), vec![("t1", Some(2), None)]);
+    assert!(diff.modified_tracks.is_empty());
+    assert!(diff.reordered_tracks.is_empty());
+  }
+
+  #[test]
+  fn diff_playlists_adds_another_copy_of_a_track() {
+    let before = playlist(vec![track("t1", "One")]);
+    let after = playlist(vec![track("t1", "One"), track("t2", "Two"), track("t1", "One")]);
+
+    let diff = diff_playlists(&before, &after);
+
+    assert_eq!(
+      positions(&diff.added_tracks),
+      vec![("t2", None, Some(1)), ("t1", None, Some(2))]
+    );
+    assert!(diff.removed_tracks.is_empty());
+    assert!(diff.modified_tracks.is_empty());
+    assert!(diff.reordered_tracks.is_empty());
+  }
+
+  #[test]
+  fn diff_playlists_matches_duplicates_by_occurrence_when_reordered() {
+    let before = playlist(vec![track("t1", "One"), track("t2", "Two"), track("t1", "One")]);
+    let after = playlist(vec![track("t2", "Two"), track("t1", "One"), track("t1", "One")]);
+
+    let diff = diff_playlists(&before, &after);
+
+    assert!(diff.added_tracks.is_empty());
+    assert!(diff.removed_tracks.is_empty());
+    assert!(diff.modified_tracks.is_empty());
+    // The first copy moves behind t2, and the second copy stays at the end
+    assert_eq!(positions(&diff.reordered_tracks), vec![("t1", Some(0), Some(1))]);
+  }
+
+  #[test]
+  fn longest_increasing_subsequence_of_sorted_values_is_all_of_them() {
+    assert_eq!(longest_increasing_subsequence(&[]), HashSet::new());
+    assert_eq!(longest_increasing_subsequence(&[0, 1, 2]), [0, 1, 2].iter().copied().collect());
+  }
+
+  #[test]
+  fn longest_increasing_subsequence_skips_the_out_of_order_values() {
+    assert_eq!(longest_increasing_subsequence(&[3, 0, 1, 2]), [1, 2, 3].iter().copied().collect());
+    assert_eq!(longest_increasing_subsequence(&[1, 2, 0, 3]), [0, 1, 3].iter().copied().collect());
+    // The values are strictly increasing
+    assert_eq!(longest_increasing_subsequence(&[2, 2, 2]).len(), 1);
+  }
+}